@@ -0,0 +1,250 @@
+use std::{
+    fs::File,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use memmap2::MmapMut;
+use nix::{
+    fcntl,
+    sys::{memfd, mman, stat},
+    unistd,
+};
+use wayland_client::{
+    Dispatch, QueueHandle,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
+    },
+};
+
+use crate::{HaruhiShotState, haruhierror::HaruhiError, utils::Size};
+
+/// capture_output_frame.
+pub(crate) fn create_shm_fd() -> std::io::Result<OwnedFd> {
+    // Only try memfd on linux and freebsd.
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    loop {
+        // Create a file that closes on successful execution and seal it's operations.
+        match memfd::memfd_create(
+            c"wayshot",
+            memfd::MFdFlags::MFD_CLOEXEC | memfd::MFdFlags::MFD_ALLOW_SEALING,
+        ) {
+            Ok(fd) => {
+                // This is only an optimization, so ignore errors.
+                // F_SEAL_SRHINK = File cannot be reduced in size.
+                // F_SEAL_SEAL = Prevent further calls to fcntl().
+                let _ = fcntl::fcntl(
+                    fd.as_fd(),
+                    fcntl::F_ADD_SEALS(
+                        fcntl::SealFlag::F_SEAL_SHRINK | fcntl::SealFlag::F_SEAL_SEAL,
+                    ),
+                );
+                return Ok(fd);
+            }
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(nix::errno::Errno::ENOSYS) => break,
+            Err(errno) => return Err(std::io::Error::from(errno)),
+        }
+    }
+
+    // Fallback to using shm_open.
+    let sys_time = SystemTime::now();
+    let mut mem_file_handle = format!(
+        "/wayshot-{}",
+        sys_time.duration_since(UNIX_EPOCH).unwrap().subsec_nanos()
+    );
+    loop {
+        match mman::shm_open(
+            // O_CREAT = Create file if does not exist.
+            // O_EXCL = Error if create and file exists.
+            // O_RDWR = Open for reading and writing.
+            // O_CLOEXEC = Close on successful execution.
+            // S_IRUSR = Set user read permission bit .
+            // S_IWUSR = Set user write permission bit.
+            mem_file_handle.as_str(),
+            fcntl::OFlag::O_CREAT
+                | fcntl::OFlag::O_EXCL
+                | fcntl::OFlag::O_RDWR
+                | fcntl::OFlag::O_CLOEXEC,
+            stat::Mode::S_IRUSR | stat::Mode::S_IWUSR,
+        ) {
+            Ok(fd) => match mman::shm_unlink(mem_file_handle.as_str()) {
+                Ok(_) => return Ok(fd),
+                Err(errno) => match unistd::close(fd.as_raw_fd()) {
+                    Ok(_) => return Err(std::io::Error::from(errno)),
+                    Err(errno) => return Err(std::io::Error::from(errno)),
+                },
+            },
+            Err(nix::errno::Errno::EEXIST) => {
+                // If a file with that handle exists then change the handle
+                mem_file_handle = format!(
+                    "/wayshot-{}",
+                    sys_time.duration_since(UNIX_EPOCH).unwrap().subsec_nanos()
+                );
+                continue;
+            }
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(errno) => return Err(std::io::Error::from(errno)),
+        }
+    }
+}
+
+/// Identify a buffer lent out by [BufferPool::acquire]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PoolBufferId(usize);
+
+/// One shm file, its wl_shm_pool and the buffer currently carved out of it
+#[derive(Debug)]
+struct PoolSlot {
+    file: File,
+    mmap: MmapMut,
+    pool: WlShmPool,
+    pool_size: usize,
+    buffer: WlBuffer,
+    size: Size<u32>,
+    format: wl_shm::Format,
+    in_use: bool,
+}
+
+impl PoolSlot {
+    fn frame_bytes(&self) -> usize {
+        4 * self.size.width as usize * self.size.height as usize
+    }
+
+    fn destroy(self) {
+        self.buffer.destroy();
+        self.pool.destroy();
+    }
+}
+
+/// Keep the shm pools and buffers alive between captures
+///
+/// Every slot owns one shm file. A slot whose buffer has the requested size and format is
+/// handed out again as is, otherwise a free slot is grown with `wl_shm_pool.resize` and gets a
+/// new buffer. So repeated captures of the same output do not need any allocation.
+#[derive(Debug, Default)]
+pub(crate) struct BufferPool {
+    slots: Vec<PoolSlot>,
+}
+
+impl BufferPool {
+    /// Get a buffer with the given size and format, it is marked as used until
+    /// [BufferPool::release_all] is called
+    pub(crate) fn acquire(
+        &mut self,
+        shm: &WlShm,
+        qh: &QueueHandle<HaruhiShotState>,
+        size: Size<u32>,
+        format: wl_shm::Format,
+    ) -> Result<PoolBufferId, HaruhiError> {
+        if let Some(index) = self
+            .slots
+            .iter()
+            .position(|slot| !slot.in_use && slot.size == size && slot.format == format)
+        {
+            self.slots[index].in_use = true;
+            return Ok(PoolBufferId(index));
+        }
+
+        let frame_bytes = 4 * size.width as usize * size.height as usize;
+        let Some(index) = self.slots.iter().position(|slot| !slot.in_use) else {
+            let file = File::from(create_shm_fd().map_err(HaruhiError::CreateShmFdFailed)?);
+            set_len(&file, frame_bytes)?;
+            let mmap = map_mut(&file)?;
+            let pool = shm.create_pool(file.as_fd(), frame_bytes as i32, qh, ());
+            let buffer = create_buffer(&pool, qh, 0, size, format);
+            self.slots.push(PoolSlot {
+                file,
+                mmap,
+                pool,
+                pool_size: frame_bytes,
+                buffer,
+                size,
+                format,
+                in_use: true,
+            });
+            return Ok(PoolBufferId(self.slots.len() - 1));
+        };
+
+        let slot = &mut self.slots[index];
+        if slot.pool_size < frame_bytes {
+            set_len(&slot.file, frame_bytes)?;
+            slot.mmap = map_mut(&slot.file)?;
+            slot.pool.resize(frame_bytes as i32);
+            slot.pool_size = frame_bytes;
+        }
+        slot.buffer.destroy();
+        slot.buffer = create_buffer(&slot.pool, qh, 0, size, format);
+        slot.size = size;
+        slot.format = format;
+        slot.in_use = true;
+        Ok(PoolBufferId(index))
+    }
+
+    pub(crate) fn buffer(&self, PoolBufferId(index): PoolBufferId) -> &WlBuffer {
+        &self.slots[index].buffer
+    }
+
+    /// The pixels of the buffer, only the part used by the frame
+    pub(crate) fn data_mut(&mut self, PoolBufferId(index): PoolBufferId) -> &mut [u8] {
+        let slot = &mut self.slots[index];
+        let frame_bytes = slot.frame_bytes();
+        &mut slot.mmap[..frame_bytes]
+    }
+
+    /// Every buffer can be used by the next capture again
+    pub(crate) fn release_all(&mut self) {
+        for slot in self.slots.iter_mut() {
+            slot.in_use = false;
+        }
+    }
+
+    /// Destroy all the buffers and pools
+    pub(crate) fn clear(&mut self) {
+        for slot in self.slots.drain(..) {
+            slot.destroy();
+        }
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+pub(crate) fn set_len(file: &File, size: usize) -> Result<(), HaruhiError> {
+    file.set_len(size as u64)
+        .map_err(|source| HaruhiError::ShmResizeFailed {
+            size: size as u64,
+            source,
+        })
+}
+
+pub(crate) fn map_mut(file: &File) -> Result<MmapMut, HaruhiError> {
+    unsafe { MmapMut::map_mut(file) }.map_err(HaruhiError::MmapFailed)
+}
+
+/// Create a buffer at the offset of the pool, without padding between the rows
+pub(crate) fn create_buffer<D>(
+    pool: &WlShmPool,
+    qh: &QueueHandle<D>,
+    offset: i32,
+    Size { width, height }: Size<u32>,
+    format: wl_shm::Format,
+) -> WlBuffer
+where
+    D: Dispatch<WlBuffer, ()> + 'static,
+{
+    pool.create_buffer(
+        offset,
+        width as i32,
+        height as i32,
+        4 * width as i32,
+        format,
+        qh,
+        (),
+    )
+}
@@ -0,0 +1,407 @@
+use std::{fs::File, os::fd::AsFd};
+
+use image::RgbaImage;
+use memmap2::MmapMut;
+use wayland_client::{
+    Proxy, QueueHandle,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
+        wl_surface::WlSurface,
+    },
+};
+
+use crate::{
+    bufferpool::{create_buffer, create_shm_fd, map_mut, set_len},
+    haruhierror::HaruhiError,
+    overlay::LayerShellState,
+    utils::Size,
+};
+
+/// Colors are 0xAARRGGBB, like [wl_shm::Format::Argb8888]
+pub(crate) type Color = u32;
+
+/// Width of a glyph of [FONT], without the spacing
+const GLYPH_WIDTH: u32 = 5;
+/// Height of a glyph of [FONT]
+pub(crate) const GLYPH_HEIGHT: u32 = 8;
+
+/// Something to draw on for the overlay surfaces
+///
+/// Drawing happens in memory, [Canvas::present] copies the pixels into one of the two shm
+/// buffers, so the buffer the compositor may still read is not touched.
+///
+/// The positions and sizes are in surface coordinates, the buffers have `scale` times more
+/// pixels so the overlay is sharp on scaled outputs.
+#[derive(Debug)]
+pub(crate) struct Canvas {
+    _file: File,
+    mmap: MmapMut,
+    pool: WlShmPool,
+    buffers: [WlBuffer; 2],
+    current: usize,
+    width: u32,
+    height: u32,
+    scale: u32,
+    pixels: Vec<Color>,
+    /// shown under everything, in buffer pixels
+    background: Option<Vec<Color>>,
+}
+
+impl Canvas {
+    pub(crate) fn new(
+        shm: &WlShm,
+        qh: &QueueHandle<LayerShellState>,
+        width: u32,
+        height: u32,
+        scale: i32,
+    ) -> Result<Self, HaruhiError> {
+        let scale = scale.max(1) as u32;
+        let (buffer_width, buffer_height) = (width * scale, height * scale);
+        let frame_bytes = 4 * buffer_width as usize * buffer_height as usize;
+        let file = File::from(create_shm_fd().map_err(HaruhiError::CreateShmFdFailed)?);
+        set_len(&file, frame_bytes * 2)?;
+        let mmap = map_mut(&file)?;
+        let pool = shm.create_pool(file.as_fd(), (frame_bytes * 2) as i32, qh, ());
+        let size = Size {
+            width: buffer_width,
+            height: buffer_height,
+        };
+        let format = wl_shm::Format::Argb8888;
+        let buffers = [
+            create_buffer(&pool, qh, 0, size, format),
+            create_buffer(&pool, qh, frame_bytes as i32, size, format),
+        ];
+        Ok(Self {
+            _file: file,
+            mmap,
+            pool,
+            buffers,
+            current: 0,
+            width,
+            height,
+            scale,
+            pixels: vec![0; buffer_width as usize * buffer_height as usize],
+            background: None,
+        })
+    }
+
+    pub(crate) fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub(crate) fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// How many buffer pixels there are in one surface pixel
+    pub(crate) fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// The size of the buffers in pixels
+    pub(crate) fn buffer_size(&self) -> (u32, u32) {
+        (self.buffer_width(), self.height * self.scale)
+    }
+
+    fn buffer_width(&self) -> u32 {
+        self.width * self.scale
+    }
+
+    /// Show the pixels under everything drawn, row by row in buffer pixels. [Canvas::fill]
+    /// blends over them and [Canvas::clear_rect] uncovers them
+    pub(crate) fn set_background(&mut self, background: Vec<Color>) {
+        debug_assert_eq!(background.len(), self.pixels.len());
+        self.background = Some(background);
+    }
+
+    /// The rectangle in buffer pixels, clipped to the buffer, as the first and last columns and
+    /// rows
+    fn buffer_rect(&self, x: i32, y: i32, width: i32, height: i32) -> (usize, usize, usize, usize) {
+        let scale = self.scale as i32;
+        let buffer_width = self.buffer_width() as i32;
+        let buffer_height = (self.height * self.scale) as i32;
+        (
+            (x * scale).clamp(0, buffer_width) as usize,
+            (y * scale).clamp(0, buffer_height) as usize,
+            ((x + width) * scale).clamp(0, buffer_width) as usize,
+            ((y + height) * scale).clamp(0, buffer_height) as usize,
+        )
+    }
+
+    /// Copy the pixels into the next buffer, and commit it to the surface
+    pub(crate) fn present(&mut self, surface: &WlSurface) {
+        self.current = (self.current + 1) % self.buffers.len();
+        let frame_bytes = self.pixels.len() * 4;
+        let start = self.current * frame_bytes;
+        for (target, pixel) in self.mmap[start..start + frame_bytes]
+            .chunks_exact_mut(4)
+            .zip(self.pixels.iter())
+        {
+            target.copy_from_slice(&pixel.to_le_bytes());
+        }
+        surface.set_buffer_scale(self.scale as i32);
+        surface.attach(Some(&self.buffers[self.current]), 0, 0);
+        // damage_buffer needs wl_surface version 4
+        if surface.version() >= 4 {
+            surface.damage_buffer(
+                0,
+                0,
+                self.buffer_width() as i32,
+                (self.height * self.scale) as i32,
+            );
+        } else {
+            surface.damage(0, 0, self.width as i32, self.height as i32);
+        }
+        surface.commit();
+    }
+
+    pub(crate) fn destroy(self) {
+        for buffer in self.buffers.iter() {
+            buffer.destroy();
+        }
+        self.pool.destroy();
+    }
+
+    pub(crate) fn fill(&mut self, color: Color) {
+        match &self.background {
+            Some(background) => {
+                for (pixel, below) in self.pixels.iter_mut().zip(background) {
+                    *pixel = blend(*below, color);
+                }
+            }
+            None => self.pixels.fill(color),
+        }
+    }
+
+    /// Fill the rectangle, blending when the color is translucent. It is clipped to the canvas
+    pub(crate) fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, color: Color) {
+        let (x0, y0, x1, y1) = self.buffer_rect(x, y, width, height);
+        for row in y0..y1 {
+            let line = row * self.buffer_width() as usize;
+            for pixel in &mut self.pixels[line + x0..line + x1] {
+                *pixel = blend(*pixel, color);
+            }
+        }
+    }
+
+    /// Make the rectangle fully transparent, or show the background again. It is clipped to the
+    /// canvas
+    pub(crate) fn clear_rect(&mut self, x: i32, y: i32, width: i32, height: i32) {
+        let (x0, y0, x1, y1) = self.buffer_rect(x, y, width, height);
+        for row in y0..y1 {
+            let line = row * self.buffer_width() as usize;
+            let range = line + x0..line + x1;
+            match &self.background {
+                Some(background) => self.pixels[range.clone()].copy_from_slice(&background[range]),
+                None => self.pixels[range].fill(0),
+            }
+        }
+    }
+
+    /// Draw the border of the rectangle, inside of it
+    pub(crate) fn stroke_rect(
+        &mut self,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+        thickness: i32,
+        color: Color,
+    ) {
+        self.fill_rect(x, y, width, thickness, color);
+        self.fill_rect(x, y + height - thickness, width, thickness, color);
+        self.fill_rect(x, y + thickness, thickness, height - thickness * 2, color);
+        self.fill_rect(
+            x + width - thickness,
+            y + thickness,
+            thickness,
+            height - thickness * 2,
+            color,
+        );
+    }
+
+    /// Draw the image with its top left corner at the position. The image is in buffer pixels,
+    /// so it is not scaled up
+    pub(crate) fn draw_image(&mut self, x: i32, y: i32, image: &RgbaImage) {
+        let buffer_width = self.buffer_width() as i32;
+        let buffer_height = (self.height * self.scale) as i32;
+        for (image_x, image_y, pixel) in image.enumerate_pixels() {
+            let target_x = x * self.scale as i32 + image_x as i32;
+            let target_y = y * self.scale as i32 + image_y as i32;
+            if target_x < 0 || target_y < 0 || target_x >= buffer_width || target_y >= buffer_height
+            {
+                continue;
+            }
+            let [r, g, b, a] = pixel.0;
+            let color = u32::from_be_bytes([a, r, g, b]);
+            let index = target_y as usize * buffer_width as usize + target_x as usize;
+            self.pixels[index] = blend(self.pixels[index], color);
+        }
+    }
+
+    /// Draw ascii text with the top left corner at the position, every pixel of the font
+    /// becomes a square of `scale` pixels. Other characters are drawn as '?'
+    pub(crate) fn draw_text(&mut self, x: i32, y: i32, text: &str, scale: u32, color: Color) {
+        let scale = scale as i32;
+        for (index, char) in text.chars().enumerate() {
+            let glyph = glyph(char);
+            let glyph_x = x + index as i32 * (GLYPH_WIDTH as i32 + 1) * scale;
+            for (column, bits) in glyph.iter().enumerate() {
+                for row in 0..GLYPH_HEIGHT as i32 {
+                    if bits & (1 << row) == 0 {
+                        continue;
+                    }
+                    self.fill_rect(
+                        glyph_x + column as i32 * scale,
+                        y + row * scale,
+                        scale,
+                        scale,
+                        color,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The width of the text drawn by [Canvas::draw_text]
+pub(crate) fn text_width(text: &str, scale: u32) -> u32 {
+    text.chars().count() as u32 * (GLYPH_WIDTH + 1) * scale
+}
+
+/// Cut the text so it fits in the width, ending with ".." when it is cut
+pub(crate) fn fit_text(text: &str, width: u32, scale: u32) -> String {
+    let max_chars = (width / ((GLYPH_WIDTH + 1) * scale)) as usize;
+    if text.chars().count() <= max_chars {
+        return text.to_owned();
+    }
+    let mut fitted: String = text.chars().take(max_chars.saturating_sub(2)).collect();
+    fitted.push_str("..");
+    fitted
+}
+
+fn blend(below: Color, above: Color) -> Color {
+    let alpha = above >> 24;
+    match alpha {
+        0 => below,
+        255 => above,
+        _ => {
+            let channel = |shift: u32| {
+                let below = (below >> shift) & 0xff;
+                let above = (above >> shift) & 0xff;
+                ((above * alpha + below * (255 - alpha)) / 255) << shift
+            };
+            0xff00_0000 | channel(16) | channel(8) | channel(0)
+        }
+    }
+}
+
+fn glyph(char: char) -> &'static [u8; 5] {
+    let index = match char {
+        ' '..='~' => char as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT[index]
+}
+
+/// A 5x8 font for the printable ascii characters. Every byte is a column, the lowest bit is the
+/// top row
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // #
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x56, 0x20, 0x50], // &
+    [0x00, 0x08, 0x07, 0x03, 0x00], // '
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // )
+    [0x2a, 0x1c, 0x7f, 0x1c, 0x2a], // *
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // +
+    [0x00, 0x80, 0x70, 0x30, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x00, 0x60, 0x60, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // 0
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // 1
+    [0x72, 0x49, 0x49, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x49, 0x4d, 0x33], // 3
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3c, 0x4a, 0x49, 0x49, 0x31], // 6
+    [0x41, 0x21, 0x11, 0x09, 0x07], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x46, 0x49, 0x49, 0x29, 0x1e], // 9
+    [0x00, 0x00, 0x14, 0x00, 0x00], // :
+    [0x00, 0x40, 0x34, 0x00, 0x00], // ;
+    [0x00, 0x08, 0x14, 0x22, 0x41], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x59, 0x09, 0x06], // ?
+    [0x3e, 0x41, 0x5d, 0x59, 0x4e], // @
+    [0x7c, 0x12, 0x11, 0x12, 0x7c], // A
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7f, 0x41, 0x41, 0x41, 0x3e], // D
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3e, 0x41, 0x41, 0x51, 0x73], // G
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // H
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // J
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7f, 0x02, 0x1c, 0x02, 0x7f], // M
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // N
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // O
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // Q
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // R
+    [0x26, 0x49, 0x49, 0x49, 0x32], // S
+    [0x03, 0x01, 0x7f, 0x01, 0x03], // T
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // U
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // V
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x03, 0x04, 0x78, 0x04, 0x03], // Y
+    [0x61, 0x59, 0x49, 0x4d, 0x43], // Z
+    [0x00, 0x7f, 0x41, 0x41, 0x41], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x41, 0x7f], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x03, 0x07, 0x08, 0x00], // `
+    [0x20, 0x54, 0x54, 0x78, 0x40], // a
+    [0x7f, 0x28, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x28], // c
+    [0x38, 0x44, 0x44, 0x28, 0x7f], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x00, 0x08, 0x7e, 0x09, 0x02], // f
+    [0x18, 0xa4, 0xa4, 0x9c, 0x78], // g
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // i
+    [0x20, 0x40, 0x40, 0x3d, 0x00], // j
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // l
+    [0x7c, 0x04, 0x78, 0x04, 0x78], // m
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0xfc, 0x18, 0x24, 0x24, 0x18], // p
+    [0x18, 0x24, 0x24, 0x18, 0xfc], // q
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x24], // s
+    [0x04, 0x04, 0x3f, 0x44, 0x24], // t
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // u
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // v
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x4c, 0x90, 0x90, 0x90, 0x7c], // y
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x77, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x02, 0x01, 0x02, 0x04, 0x02], // ~
+];
@@ -0,0 +1,302 @@
+use wayland_client::{
+    Connection, Proxy,
+    globals::{GlobalList, registry_queue_init},
+    protocol::wl_data_device_manager::WlDataDeviceManager,
+};
+use wayland_protocols::{
+    ext::{
+        data_control::v1::client::ext_data_control_manager_v1::ExtDataControlManagerV1,
+        foreign_toplevel_list::v1::client::ext_foreign_toplevel_list_v1::ExtForeignToplevelListV1,
+        image_capture_source::v1::client::{
+            ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1,
+            ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1,
+        },
+        image_copy_capture::v1::client::ext_image_copy_capture_manager_v1::ExtImageCopyCaptureManagerV1,
+    },
+    wp::viewporter::client::wp_viewporter::WpViewporter,
+    xdg::xdg_output::zv1::client::zxdg_output_manager_v1::ZxdgOutputManagerV1,
+};
+use wayland_protocols_wlr::{
+    data_control::v1::client::zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1,
+    layer_shell::v1::client::zwlr_layer_shell_v1::ZwlrLayerShellV1,
+    output_management::v1::client::zwlr_output_manager_v1::ZwlrOutputManagerV1,
+    screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+};
+
+use crate::{HaruhiShotState, haruhierror::HaruhiError};
+
+/// The wayland protocols haruhishot cares about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    ImageCopyCapture,
+    OutputImageCaptureSource,
+    ToplevelImageCaptureSource,
+    ForeignToplevelList,
+    XdgOutput,
+    LayerShell,
+    Viewporter,
+    /// Not used for capture, only reported so users know why their compositor does not work
+    WlrScreencopy,
+    /// Only for the activated, maximized and fullscreen state of the toplevels
+    WlrForeignToplevelManagement,
+    /// Only for the serial of the monitors
+    WlrOutputManagement,
+    /// The clipboard, wlr data control is used when it is missing
+    ExtDataControl,
+    WlrDataControl,
+    /// The clipboard without data control, with layer shell for the keyboard focus
+    DataDeviceManager,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 13] = [
+        Protocol::ImageCopyCapture,
+        Protocol::OutputImageCaptureSource,
+        Protocol::ToplevelImageCaptureSource,
+        Protocol::ForeignToplevelList,
+        Protocol::XdgOutput,
+        Protocol::LayerShell,
+        Protocol::Viewporter,
+        Protocol::WlrScreencopy,
+        Protocol::WlrForeignToplevelManagement,
+        Protocol::WlrOutputManagement,
+        Protocol::ExtDataControl,
+        Protocol::WlrDataControl,
+        Protocol::DataDeviceManager,
+    ];
+
+    /// The name of the global interface
+    pub fn interface(&self) -> &'static str {
+        match self {
+            Protocol::ImageCopyCapture => ExtImageCopyCaptureManagerV1::interface().name,
+            Protocol::OutputImageCaptureSource => {
+                ExtOutputImageCaptureSourceManagerV1::interface().name
+            }
+            Protocol::ToplevelImageCaptureSource => {
+                ExtForeignToplevelImageCaptureSourceManagerV1::interface().name
+            }
+            Protocol::ForeignToplevelList => ExtForeignToplevelListV1::interface().name,
+            Protocol::XdgOutput => ZxdgOutputManagerV1::interface().name,
+            Protocol::LayerShell => ZwlrLayerShellV1::interface().name,
+            Protocol::Viewporter => WpViewporter::interface().name,
+            Protocol::WlrScreencopy => ZwlrScreencopyManagerV1::interface().name,
+            Protocol::WlrForeignToplevelManagement => {
+                ZwlrForeignToplevelManagerV1::interface().name
+            }
+            Protocol::WlrOutputManagement => ZwlrOutputManagerV1::interface().name,
+            Protocol::ExtDataControl => ExtDataControlManagerV1::interface().name,
+            Protocol::WlrDataControl => ZwlrDataControlManagerV1::interface().name,
+            Protocol::DataDeviceManager => WlDataDeviceManager::interface().name,
+        }
+    }
+
+    /// The lowest version haruhishot binds
+    pub fn min_version(&self) -> u32 {
+        match self {
+            Protocol::XdgOutput => 3,
+            // the serial number of the heads is sent since version 2
+            Protocol::WlrOutputManagement => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// What haruhishot can do, and the protocols each of them needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// capture a single output, or all of them
+    OutputCapture,
+    /// capture a window
+    ToplevelCapture,
+    /// capture the focused window
+    FocusedToplevelCapture,
+    /// capture an area or pick a color, the selector is drawn on layer shell surfaces
+    AreaCapture,
+    /// freeze the screens while selecting an area or a color
+    FreezeOverlay,
+    /// the layout of the outputs is exact even with fractional scale, otherwise it is
+    /// calculated from wl_output
+    ExactLayout,
+    /// copy to the clipboard
+    Clipboard,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 7] = [
+        Feature::OutputCapture,
+        Feature::ToplevelCapture,
+        Feature::FocusedToplevelCapture,
+        Feature::AreaCapture,
+        Feature::FreezeOverlay,
+        Feature::ExactLayout,
+        Feature::Clipboard,
+    ];
+
+    /// The protocols the feature needs. [Feature::Clipboard] needs one of the data controls,
+    /// or wl_data_device_manager with layer shell to take the keyboard focus
+    pub fn required_protocols(&self) -> &'static [Protocol] {
+        match self {
+            Feature::OutputCapture => &[
+                Protocol::ImageCopyCapture,
+                Protocol::OutputImageCaptureSource,
+            ],
+            Feature::AreaCapture => &[
+                Protocol::ImageCopyCapture,
+                Protocol::OutputImageCaptureSource,
+                Protocol::LayerShell,
+            ],
+            Feature::ToplevelCapture => &[
+                Protocol::ImageCopyCapture,
+                Protocol::ToplevelImageCaptureSource,
+                Protocol::ForeignToplevelList,
+            ],
+            Feature::FocusedToplevelCapture => &[
+                Protocol::ImageCopyCapture,
+                Protocol::ToplevelImageCaptureSource,
+                Protocol::ForeignToplevelList,
+                Protocol::WlrForeignToplevelManagement,
+            ],
+            Feature::FreezeOverlay => &[Protocol::LayerShell, Protocol::Viewporter],
+            Feature::ExactLayout => &[Protocol::XdgOutput],
+            Feature::Clipboard => &[
+                Protocol::ExtDataControl,
+                Protocol::WlrDataControl,
+                Protocol::DataDeviceManager,
+                Protocol::LayerShell,
+            ],
+        }
+    }
+}
+
+/// The versions of the protocols the compositor provides, None if it is missing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub image_copy_capture: Option<u32>,
+    pub output_image_capture_source: Option<u32>,
+    pub toplevel_image_capture_source: Option<u32>,
+    pub foreign_toplevel_list: Option<u32>,
+    pub xdg_output: Option<u32>,
+    pub layer_shell: Option<u32>,
+    pub viewporter: Option<u32>,
+    pub wlr_screencopy: Option<u32>,
+    pub wlr_foreign_toplevel_management: Option<u32>,
+    pub wlr_output_management: Option<u32>,
+    pub ext_data_control: Option<u32>,
+    pub wlr_data_control: Option<u32>,
+    pub data_device_manager: Option<u32>,
+}
+
+impl Capabilities {
+    /// Read the capabilities from the globals of the registry
+    pub fn from_globals(globals: &GlobalList) -> Self {
+        let mut capabilities = Self::default();
+        globals.contents().with_list(|list| {
+            for global in list {
+                let Some(protocol) = Protocol::ALL
+                    .into_iter()
+                    .find(|protocol| protocol.interface() == global.interface)
+                else {
+                    continue;
+                };
+                *capabilities.version_mut(protocol) = Some(global.version);
+            }
+        });
+        capabilities
+    }
+
+    /// Connect to the compositor and read the capabilities, it works even if [HaruhiShotState]
+    /// cannot be created
+    pub fn probe() -> Result<Self, HaruhiError> {
+        let conn = Connection::connect_to_env()?;
+        let (globals, _) = registry_queue_init::<HaruhiShotState>(&conn)?;
+        Ok(Self::from_globals(&globals))
+    }
+
+    pub fn version(&self, protocol: Protocol) -> Option<u32> {
+        match protocol {
+            Protocol::ImageCopyCapture => self.image_copy_capture,
+            Protocol::OutputImageCaptureSource => self.output_image_capture_source,
+            Protocol::ToplevelImageCaptureSource => self.toplevel_image_capture_source,
+            Protocol::ForeignToplevelList => self.foreign_toplevel_list,
+            Protocol::XdgOutput => self.xdg_output,
+            Protocol::LayerShell => self.layer_shell,
+            Protocol::Viewporter => self.viewporter,
+            Protocol::WlrScreencopy => self.wlr_screencopy,
+            Protocol::WlrForeignToplevelManagement => self.wlr_foreign_toplevel_management,
+            Protocol::WlrOutputManagement => self.wlr_output_management,
+            Protocol::ExtDataControl => self.ext_data_control,
+            Protocol::WlrDataControl => self.wlr_data_control,
+            Protocol::DataDeviceManager => self.data_device_manager,
+        }
+    }
+
+    fn version_mut(&mut self, protocol: Protocol) -> &mut Option<u32> {
+        match protocol {
+            Protocol::ImageCopyCapture => &mut self.image_copy_capture,
+            Protocol::OutputImageCaptureSource => &mut self.output_image_capture_source,
+            Protocol::ToplevelImageCaptureSource => &mut self.toplevel_image_capture_source,
+            Protocol::ForeignToplevelList => &mut self.foreign_toplevel_list,
+            Protocol::XdgOutput => &mut self.xdg_output,
+            Protocol::LayerShell => &mut self.layer_shell,
+            Protocol::Viewporter => &mut self.viewporter,
+            Protocol::WlrScreencopy => &mut self.wlr_screencopy,
+            Protocol::WlrForeignToplevelManagement => &mut self.wlr_foreign_toplevel_management,
+            Protocol::WlrOutputManagement => &mut self.wlr_output_management,
+            Protocol::ExtDataControl => &mut self.ext_data_control,
+            Protocol::WlrDataControl => &mut self.wlr_data_control,
+            Protocol::DataDeviceManager => &mut self.data_device_manager,
+        }
+    }
+
+    /// The protocol is there, with a version haruhishot can bind
+    pub fn supports(&self, protocol: Protocol) -> bool {
+        self.version(protocol)
+            .is_some_and(|version| version >= protocol.min_version())
+    }
+
+    /// The protocols missing for the feature, empty if it works
+    pub fn missing(&self, feature: Feature) -> Vec<Protocol> {
+        if feature == Feature::Clipboard
+            && (self.supports(Protocol::ExtDataControl)
+                || self.supports(Protocol::WlrDataControl)
+                || (self.supports(Protocol::DataDeviceManager)
+                    && self.supports(Protocol::LayerShell)))
+        {
+            return Vec::new();
+        }
+        feature
+            .required_protocols()
+            .iter()
+            .copied()
+            .filter(|protocol| !self.supports(*protocol))
+            .collect()
+    }
+}
+
+impl HaruhiShotState {
+    /// report which protocols the compositor provides
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_globals(self.globals())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capabilities, Feature};
+
+    #[test]
+    fn clipboard_needs_data_control_or_both_fallbacks() {
+        let mut capabilities = Capabilities {
+            layer_shell: Some(4),
+            ..Default::default()
+        };
+        assert!(!capabilities.missing(Feature::Clipboard).is_empty());
+        capabilities.data_device_manager = Some(3);
+        assert!(capabilities.missing(Feature::Clipboard).is_empty());
+        capabilities.layer_shell = None;
+        assert!(!capabilities.missing(Feature::Clipboard).is_empty());
+        capabilities.wlr_data_control = Some(2);
+        assert!(capabilities.missing(Feature::Clipboard).is_empty());
+    }
+}
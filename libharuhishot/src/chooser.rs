@@ -0,0 +1,465 @@
+use image::{ColorType, DynamicImage, ImageBuffer, RgbaImage, imageops};
+use tracing::debug;
+use wayland_client::{
+    EventQueue, Proxy,
+    protocol::{
+        wl_compositor::WlCompositor, wl_output::WlOutput, wl_seat::WlSeat, wl_surface::WlSurface,
+    },
+};
+use wayland_protocols::wp::cursor_shape::v1::client::{
+    wp_cursor_shape_device_v1::Shape, wp_cursor_shape_manager_v1::WpCursorShapeManagerV1,
+};
+use wayland_protocols_wlr::layer_shell::v1::client::{
+    zwlr_layer_shell_v1::{Layer, ZwlrLayerShellV1},
+    zwlr_layer_surface_v1::{Anchor, KeyboardInteractivity, ZwlrLayerSurfaceV1},
+};
+
+use crate::{
+    CaptureOption, HaruhiShotState, TopLevel, WlOutputInfo,
+    canvas::{Canvas, Color, GLYPH_HEIGHT, fit_text, text_width},
+    dispatch::{DispatchLimit, dispatch_both_with_limit, dispatch_with_limit},
+    haruhierror::HaruhiError,
+    overlay::{BTN_LEFT, LayerShellState, OverlayInput, keycode},
+    screenshot::{ImageInfo, LiveCapture},
+};
+
+const MARGIN: i32 = 48;
+const PADDING: i32 = 12;
+const MAX_CELL_WIDTH: i32 = 480;
+const TEXT_SCALE: u32 = 2;
+const LINE_HEIGHT: i32 = (GLYPH_HEIGHT * TEXT_SCALE) as i32 + 6;
+const LABEL_HEIGHT: i32 = LINE_HEIGHT * 2;
+
+const BACKGROUND: Color = 0xd0_10_10_10;
+const CELL: Color = 0xff_2a_2a_2a;
+const SELECTED: Color = 0xff_3d_ae_e9;
+const TEXT: Color = 0xff_ee_ee_ee;
+const TEXT_DIM: Color = 0xff_a0_a0_a0;
+const HINT: &str = "Click or use the arrows and Enter to choose a window, Esc to cancel";
+
+/// Where the cells of the chooser are on one surface
+#[derive(Debug, Clone, Copy)]
+struct Grid {
+    columns: usize,
+    count: usize,
+    cell_width: i32,
+    cell_height: i32,
+    origin_x: i32,
+    origin_y: i32,
+}
+
+impl Grid {
+    fn new(count: usize, width: i32, height: i32) -> Self {
+        let columns = (count as f64).sqrt().ceil().max(1.0) as usize;
+        let rows = count.div_ceil(columns).max(1) as i32;
+        let mut cell_width = ((width - MARGIN * 2) / columns as i32).min(MAX_CELL_WIDTH);
+        let mut cell_height = (cell_width - PADDING * 2) * 9 / 16 + LABEL_HEIGHT + PADDING * 3;
+        if cell_height * rows > height - MARGIN * 2 {
+            cell_height = (height - MARGIN * 2) / rows;
+            let thumbnail_height = cell_height - LABEL_HEIGHT - PADDING * 3;
+            cell_width = cell_width.min(thumbnail_height * 16 / 9 + PADDING * 2);
+        }
+        Self {
+            columns,
+            count,
+            cell_width,
+            cell_height,
+            origin_x: (width - cell_width * columns as i32) / 2,
+            origin_y: (height - cell_height * rows) / 2,
+        }
+    }
+
+    /// x, y, width and height of the cell
+    fn cell(&self, index: usize) -> (i32, i32, i32, i32) {
+        let column = (index % self.columns) as i32;
+        let row = (index / self.columns) as i32;
+        (
+            self.origin_x + column * self.cell_width,
+            self.origin_y + row * self.cell_height,
+            self.cell_width,
+            self.cell_height,
+        )
+    }
+
+    fn thumbnail_size(&self) -> (u32, u32) {
+        (
+            (self.cell_width - PADDING * 2).max(1) as u32,
+            (self.cell_height - LABEL_HEIGHT - PADDING * 3).max(1) as u32,
+        )
+    }
+
+    fn hit(&self, x: f64, y: f64) -> Option<usize> {
+        (0..self.count).find(|index| {
+            let (cell_x, cell_y, width, height) = self.cell(*index);
+            x >= cell_x as f64
+                && y >= cell_y as f64
+                && x < (cell_x + width) as f64
+                && y < (cell_y + height) as f64
+        })
+    }
+
+    /// The selection after moving it with the arrow key, it stops at the edges
+    fn step(&self, selected: usize, code: u32) -> usize {
+        match code {
+            keycode::LEFT => selected.saturating_sub(1),
+            keycode::RIGHT | keycode::TAB => (selected + 1).min(self.count - 1),
+            keycode::UP => selected.checked_sub(self.columns).unwrap_or(selected),
+            keycode::DOWN if selected + self.columns < self.count => selected + self.columns,
+            _ => selected,
+        }
+    }
+}
+
+/// The capture sessions of the toplevels, on the event queue of the state
+struct LiveThumbnails {
+    queue: EventQueue<HaruhiShotState>,
+    /// None when the toplevel cannot be captured
+    captures: Vec<Option<LiveCapture>>,
+}
+
+/// The chooser on one output
+struct ChooserSurface {
+    output: WlOutput,
+    scale: i32,
+    surface: WlSurface,
+    layer_surface: ZwlrLayerSurfaceV1,
+    canvas: Option<Canvas>,
+    grid: Grid,
+    thumbnails: Vec<Option<RgbaImage>>,
+}
+
+impl ChooserSurface {
+    /// Shrink the image to fit in the cell, in buffer pixels so it is sharp on scaled outputs
+    fn set_thumbnail(&mut self, index: usize, image: &RgbaImage) {
+        let (thumbnail_width, thumbnail_height) = self.grid.thumbnail_size();
+        let buffer_scale = self.scale.max(1) as u32;
+        let (thumbnail_width, thumbnail_height) = (
+            thumbnail_width * buffer_scale,
+            thumbnail_height * buffer_scale,
+        );
+        let scale = (thumbnail_width as f64 / image.width() as f64)
+            .min(thumbnail_height as f64 / image.height() as f64)
+            .min(1.0);
+        self.thumbnails[index] = Some(imageops::thumbnail(
+            image,
+            ((image.width() as f64 * scale) as u32).max(1),
+            ((image.height() as f64 * scale) as u32).max(1),
+        ));
+    }
+
+    fn draw(&mut self, toplevels: &[TopLevel], selected: usize) {
+        let Some(canvas) = self.canvas.as_mut() else {
+            return;
+        };
+        canvas.fill(BACKGROUND);
+        let hint = fit_text(HINT, canvas.width(), TEXT_SCALE);
+        let hint_x = (canvas.width() as i32 - text_width(&hint, TEXT_SCALE) as i32) / 2;
+        canvas.draw_text(hint_x, MARGIN / 2, &hint, TEXT_SCALE, TEXT_DIM);
+
+        for (index, toplevel) in toplevels.iter().enumerate() {
+            let (x, y, width, height) = self.grid.cell(index);
+            canvas.fill_rect(x + 4, y + 4, width - 8, height - 8, CELL);
+            if index == selected {
+                canvas.stroke_rect(x + 4, y + 4, width - 8, height - 8, 4, SELECTED);
+            }
+            let (thumbnail_width, thumbnail_height) = self.grid.thumbnail_size();
+            if let Some(thumbnail) = &self.thumbnails[index] {
+                // the thumbnail is in buffer pixels
+                let scale = canvas.scale();
+                canvas.draw_image(
+                    x + PADDING
+                        + (thumbnail_width * scale - thumbnail.width()) as i32 / 2 / scale as i32,
+                    y + PADDING
+                        + (thumbnail_height * scale - thumbnail.height()) as i32 / 2 / scale as i32,
+                    thumbnail,
+                );
+            }
+            let label_y = y + PADDING * 2 + thumbnail_height as i32;
+            let label_width = thumbnail_width;
+            let app_id = fit_text(toplevel.app_id(), label_width, TEXT_SCALE);
+            canvas.draw_text(x + PADDING, label_y, &app_id, TEXT_SCALE, TEXT);
+            let title = fit_text(toplevel.title(), label_width, TEXT_SCALE);
+            canvas.draw_text(
+                x + PADDING,
+                label_y + LINE_HEIGHT,
+                &title,
+                TEXT_SCALE,
+                TEXT_DIM,
+            );
+        }
+        canvas.present(&self.surface);
+    }
+
+    fn destroy(self) {
+        self.surface.attach(None, 0, 0);
+        self.surface.commit();
+        self.layer_surface.destroy();
+        self.surface.destroy();
+        if let Some(canvas) = self.canvas {
+            canvas.destroy();
+        }
+    }
+}
+
+impl HaruhiShotState {
+    /// Show the toplevels as a grid of thumbnails with their app id and title on every output,
+    /// and let the user choose one with the pointer or the keyboard.
+    ///
+    /// The thumbnails are live, every toplevel keeps a capture session while the chooser is
+    /// open and the thumbnail is updated when the toplevel changes. It fails with
+    /// [HaruhiError::Cancelled] when the user presses Escape or the right button, and with
+    /// [HaruhiError::NoOutput] when there is no output to show it on. Needs layer shell and a
+    /// seat.
+    pub fn pick_toplevel(&mut self, option: CaptureOption) -> Result<TopLevel, HaruhiError> {
+        let toplevels = self.toplevels().clone();
+        if toplevels.is_empty() {
+            return Err(HaruhiError::NoToplevel);
+        }
+        if self.outputs().is_empty() {
+            return Err(HaruhiError::NoOutput);
+        }
+        let mut state = LayerShellState::new();
+        let mut event_queue: EventQueue<LayerShellState> = self.connection().new_event_queue();
+        let qh = event_queue.handle();
+        let globals = self.globals();
+        let compositor = globals.bind::<WlCompositor, _, _>(&qh, 3..=6, ())?;
+        let layer_shell = globals.bind::<ZwlrLayerShellV1, _, _>(&qh, 1..=4, ())?;
+        let cursor_shape_manager = globals
+            .bind::<WpCursorShapeManagerV1, _, _>(&qh, 1..=1, ())
+            .ok();
+        state.set_cursor_shape(cursor_shape_manager.clone(), Shape::Default);
+        let seat = globals.bind::<WlSeat, _, _>(&qh, 1..=7, ())?;
+
+        let mut surfaces = Vec::with_capacity(self.outputs().len());
+        for WlOutputInfo { output, scale, .. } in self.outputs().iter() {
+            let surface = compositor.create_surface(&qh, ());
+            let layer_surface = layer_shell.get_layer_surface(
+                &surface,
+                Some(output),
+                Layer::Overlay,
+                "haruhishot-chooser".to_string(),
+                &qh,
+                output.clone(),
+            );
+            layer_surface.set_exclusive_zone(-1);
+            layer_surface.set_anchor(Anchor::all());
+            layer_surface.set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
+            surface.commit();
+            surfaces.push(ChooserSurface {
+                output: output.clone(),
+                scale: *scale,
+                surface,
+                layer_surface,
+                canvas: None,
+                grid: Grid::new(toplevels.len(), 0, 0),
+                thumbnails: Vec::new(),
+            });
+        }
+
+        self.buffer_pool_mut().release_all();
+        let captures = toplevels
+            .iter()
+            .map(|toplevel| match self.live_capture(toplevel, option) {
+                Ok(capture) => Some(capture),
+                Err(err) => {
+                    debug!("No thumbnail for {}: {err}", toplevel.id_and_title());
+                    None
+                }
+            })
+            .collect();
+        let mut thumbnails = LiveThumbnails {
+            queue: self.take_event_queue()?,
+            captures,
+        };
+
+        let limit = self.interaction_limit();
+        let result = self.run_chooser(
+            &mut event_queue,
+            &mut state,
+            &mut surfaces,
+            &toplevels,
+            &mut thumbnails,
+            &limit,
+        );
+
+        for surface in surfaces {
+            surface.destroy();
+        }
+        for capture in thumbnails.captures.into_iter().flatten() {
+            capture.destroy();
+        }
+        self.reset_event_queue(thumbnails.queue);
+        state.release_input();
+        if seat.version() >= 5 {
+            seat.release();
+        }
+        if let Some(manager) = cursor_shape_manager {
+            manager.destroy();
+        }
+        event_queue.roundtrip(&mut state)?;
+        result.map(|index| toplevels[index].clone())
+    }
+
+    fn run_chooser(
+        &mut self,
+        event_queue: &mut EventQueue<LayerShellState>,
+        state: &mut LayerShellState,
+        surfaces: &mut [ChooserSurface],
+        toplevels: &[TopLevel],
+        thumbnails: &mut LiveThumbnails,
+        limit: &DispatchLimit,
+    ) -> Result<usize, HaruhiError> {
+        for chooser_surface in surfaces.iter_mut() {
+            while !state.configured_outputs.contains(&chooser_surface.output) {
+                if state.closed {
+                    return Err(HaruhiError::Cancelled);
+                }
+                dispatch_with_limit(event_queue, state, limit)?;
+            }
+            let (width, height) = state
+                .configured_sizes
+                .get(&chooser_surface.output)
+                .copied()
+                .unwrap_or_default();
+            chooser_surface.grid = Grid::new(toplevels.len(), width as i32, height as i32);
+            chooser_surface.thumbnails = vec![None; toplevels.len()];
+            if width > 0 && height > 0 {
+                chooser_surface.canvas = Some(Canvas::new(
+                    self.shm(),
+                    &event_queue.handle(),
+                    width,
+                    height,
+                    chooser_surface.scale,
+                )?);
+            }
+        }
+
+        let mut selected = 0;
+        let mut redraw = true;
+        loop {
+            if state.closed {
+                return Err(HaruhiError::Cancelled);
+            }
+            for (index, capture) in thumbnails.captures.iter_mut().enumerate() {
+                let Some(capture) = capture else {
+                    continue;
+                };
+                match self.poll_live_capture(capture) {
+                    Ok(Some(info)) => {
+                        let Some(image) = rgba_image(info) else {
+                            continue;
+                        };
+                        for chooser_surface in surfaces.iter_mut() {
+                            chooser_surface.set_thumbnail(index, &image);
+                        }
+                        redraw = true;
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        debug!(
+                            "Thumbnail of {} stopped: {err}",
+                            toplevels[index].id_and_title()
+                        );
+                    }
+                }
+            }
+            if redraw {
+                for chooser_surface in surfaces.iter_mut() {
+                    chooser_surface.draw(toplevels, selected);
+                }
+                redraw = false;
+            }
+            let wake = thumbnails
+                .captures
+                .iter()
+                .flatten()
+                .filter_map(LiveCapture::next_frame_due)
+                .chain(state.next_repeat())
+                .min();
+            dispatch_both_with_limit(event_queue, state, &mut thumbnails.queue, self, limit, wake)?;
+            state.repeat_keys();
+            for input in std::mem::take(&mut state.inputs) {
+                let previous = selected;
+                match input {
+                    OverlayInput::PointerMotion { surface, x, y } => {
+                        if let Some(index) = hit(surfaces, &surface, x, y) {
+                            selected = index;
+                        }
+                    }
+                    OverlayInput::PointerButton {
+                        surface,
+                        x,
+                        y,
+                        button,
+                        pressed: true,
+                    } => {
+                        if button != BTN_LEFT {
+                            return Err(HaruhiError::Cancelled);
+                        }
+                        if let Some(index) = hit(surfaces, &surface, x, y) {
+                            return Ok(index);
+                        }
+                    }
+                    OverlayInput::Key {
+                        code,
+                        pressed: true,
+                        ..
+                    } => match code {
+                        keycode::ESC => return Err(HaruhiError::Cancelled),
+                        keycode::ENTER | keycode::KP_ENTER => return Ok(selected),
+                        code => {
+                            if let Some(surface) = surfaces.first() {
+                                selected = surface.grid.step(selected, code);
+                            }
+                        }
+                    },
+                    _ => {}
+                }
+                redraw |= previous != selected;
+            }
+        }
+    }
+}
+
+/// The image for a thumbnail, the other color types are converted. None when the data does
+/// not fit the size
+fn rgba_image(
+    ImageInfo {
+        data,
+        width,
+        height,
+        color_type,
+        ..
+    }: ImageInfo,
+) -> Option<RgbaImage> {
+    let words = || -> Vec<u16> {
+        data.chunks_exact(2)
+            .map(|word| u16::from_ne_bytes([word[0], word[1]]))
+            .collect()
+    };
+    let image = match color_type {
+        ColorType::Rgba8 => return RgbaImage::from_raw(width, height, data),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, data)?),
+        ColorType::L8 => DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, data)?),
+        ColorType::La8 => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, data)?),
+        ColorType::Rgb16 => {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, words())?)
+        }
+        ColorType::Rgba16 => {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, words())?)
+        }
+        ColorType::L16 => DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, words())?),
+        ColorType::La16 => {
+            DynamicImage::ImageLumaA16(ImageBuffer::from_raw(width, height, words())?)
+        }
+        _ => return None,
+    };
+    Some(image.to_rgba8())
+}
+
+fn hit(surfaces: &[ChooserSurface], surface: &WlSurface, x: f64, y: f64) -> Option<usize> {
+    surfaces
+        .iter()
+        .find(|chooser_surface| &chooser_surface.surface == surface)
+        .and_then(|chooser_surface| chooser_surface.grid.hit(x, y))
+}
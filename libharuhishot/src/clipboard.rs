@@ -0,0 +1,548 @@
+use std::{
+    fs::File,
+    io::Write,
+    os::fd::{AsFd, OwnedFd},
+    sync::Arc,
+    time::Duration,
+};
+
+use tracing::debug;
+use wayland_client::{
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum, delegate_noop,
+    event_created_child,
+    globals::{GlobalList, GlobalListContents, registry_queue_init},
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_compositor::WlCompositor,
+        wl_data_device::{self, WlDataDevice},
+        wl_data_device_manager::WlDataDeviceManager,
+        wl_data_offer::WlDataOffer,
+        wl_data_source::{self, WlDataSource},
+        wl_keyboard::{self, WlKeyboard},
+        wl_registry,
+        wl_seat::{self, WlSeat},
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
+        wl_surface::WlSurface,
+    },
+};
+use wayland_protocols::ext::data_control::v1::client::{
+    ext_data_control_device_v1::{self, ExtDataControlDeviceV1},
+    ext_data_control_manager_v1::ExtDataControlManagerV1,
+    ext_data_control_offer_v1::ExtDataControlOfferV1,
+    ext_data_control_source_v1::{self, ExtDataControlSourceV1},
+};
+use wayland_protocols_wlr::{
+    data_control::v1::client::{
+        zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+        zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+        zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+        zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
+    },
+    layer_shell::v1::client::{
+        zwlr_layer_shell_v1::{Layer, ZwlrLayerShellV1},
+        zwlr_layer_surface_v1::{self, KeyboardInteractivity, ZwlrLayerSurfaceV1},
+    },
+};
+
+use crate::{
+    bufferpool::{create_buffer, create_shm_fd, set_len},
+    dispatch::{DispatchLimit, dispatch_with_limit},
+    haruhierror::HaruhiError,
+    utils::Size,
+};
+
+/// How long to wait for the keyboard focus, which wl_data_device needs
+const FOCUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The mime types text clients ask for
+const TEXT_MIME_TYPES: [&str; 5] = [
+    "text/plain;charset=utf-8",
+    "text/plain",
+    "UTF8_STRING",
+    "STRING",
+    "TEXT",
+];
+
+/// The data put on the clipboard, for each mime type it is offered as
+#[derive(Debug, Clone, Default)]
+pub struct ClipboardContent {
+    offers: Vec<(String, Arc<[u8]>)>,
+}
+
+impl ClipboardContent {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Text, offered with the mime types text clients ask for
+    pub fn text(text: &str) -> Self {
+        let data: Arc<[u8]> = text.as_bytes().into();
+        Self {
+            offers: TEXT_MIME_TYPES
+                .iter()
+                .map(|mime_type| (mime_type.to_string(), data.clone()))
+                .collect(),
+        }
+    }
+
+    /// Offer the data as the mime type too. The mime types offered first are preferred
+    pub fn with_offer(mut self, mime_type: impl Into<String>, data: impl Into<Arc<[u8]>>) -> Self {
+        self.offers.push((mime_type.into(), data.into()));
+        self
+    }
+
+    pub fn mime_types(&self) -> impl Iterator<Item = &str> {
+        self.offers.iter().map(|(mime_type, _)| mime_type.as_str())
+    }
+
+    fn data(&self, mime_type: &str) -> Option<&[u8]> {
+        self.offers
+            .iter()
+            .find(|(offered, _)| offered == mime_type)
+            .map(|(_, data)| data.as_ref())
+    }
+
+    /// Own the clipboard until another client replaces it. It blocks, so the data can be
+    /// pasted as long as it runs.
+    ///
+    /// It uses ext data control, or wlr data control. Without them it falls back to
+    /// wl_data_device, which needs the keyboard focus, so a transparent pixel takes it for a
+    /// moment. It makes its own connection, so it can be called in a process forked after the
+    /// capture.
+    pub fn serve(&self) -> Result<(), HaruhiError> {
+        self.serve_with(|| {})
+    }
+
+    /// Like [ClipboardContent::serve], and call `owned` once the compositor has handled the
+    /// selection, so a parent process can tell whether the copy worked
+    pub fn serve_with(&self, owned: impl FnOnce()) -> Result<(), HaruhiError> {
+        let conn = Connection::connect_to_env()?;
+        let (globals, mut event_queue) = registry_queue_init::<ClipboardState>(&conn)?;
+        let qh = event_queue.handle();
+        let seat = globals.bind::<WlSeat, _, _>(&qh, 1..=7, ())?;
+        let mut state = ClipboardState {
+            content: self.clone(),
+            cancelled: false,
+            keyboard: None,
+            focus_serial: None,
+        };
+
+        let control = if let Ok(manager) =
+            globals.bind::<ExtDataControlManagerV1, _, _>(&qh, 1..=1, ())
+        {
+            let source = manager.create_data_source(&qh, ());
+            for mime_type in self.mime_types() {
+                source.offer(mime_type.to_string());
+            }
+            let device = manager.get_data_device(&seat, &qh, ());
+            device.set_selection(Some(&source));
+            DataControl::Ext {
+                manager,
+                device,
+                source,
+            }
+        } else if let Ok(manager) = globals.bind::<ZwlrDataControlManagerV1, _, _>(&qh, 1..=2, ()) {
+            let source = manager.create_data_source(&qh, ());
+            for mime_type in self.mime_types() {
+                source.offer(mime_type.to_string());
+            }
+            let device = manager.get_data_device(&seat, &qh, ());
+            device.set_selection(Some(&source));
+            DataControl::Wlr {
+                manager,
+                device,
+                source,
+            }
+        } else if let Ok(manager) = globals.bind::<WlDataDeviceManager, _, _>(&qh, 1..=3, ()) {
+            let (serial, focus) = focus_serial(&globals, &mut event_queue, &mut state)?;
+            let source = manager.create_data_source(&qh, ());
+            for mime_type in self.mime_types() {
+                source.offer(mime_type.to_string());
+            }
+            let device = manager.get_data_device(&seat, &qh, ());
+            device.set_selection(Some(&source), serial);
+            // the focus must last until the compositor has handled set_selection
+            let roundtrip = event_queue.roundtrip(&mut state);
+            focus.destroy();
+            let control = DataControl::Core { device, source };
+            if let Err(error) = roundtrip {
+                control.destroy();
+                return Err(error.into());
+            }
+            control
+        } else {
+            return Err(HaruhiError::ClipboardNotSupported);
+        };
+        // wl_data_device has waited for set_selection already, before giving up the focus
+        if !matches!(control, DataControl::Core { .. })
+            && let Err(error) = event_queue.roundtrip(&mut state)
+        {
+            control.destroy();
+            return Err(error.into());
+        }
+        owned();
+
+        let result = loop {
+            if state.cancelled {
+                break Ok(());
+            }
+            if let Err(error) = event_queue.blocking_dispatch(&mut state) {
+                break Err(error.into());
+            }
+        };
+        control.destroy();
+        if let Some(keyboard) = state.keyboard.take()
+            && keyboard.version() >= 3
+        {
+            keyboard.release();
+        }
+        if seat.version() >= 5 {
+            seat.release();
+        }
+        let _ = event_queue.roundtrip(&mut state);
+        result
+    }
+}
+
+/// The transparent pixel that takes the keyboard focus
+struct FocusSurface {
+    layer_surface: ZwlrLayerSurfaceV1,
+    surface: WlSurface,
+    buffer: WlBuffer,
+    pool: WlShmPool,
+}
+
+impl FocusSurface {
+    fn destroy(self) {
+        self.layer_surface.destroy();
+        self.surface.destroy();
+        self.buffer.destroy();
+        self.pool.destroy();
+    }
+}
+
+/// Map a transparent pixel on the overlay layer with the keyboard, until the keyboard focus
+/// comes with its serial. The pixel is kept mapped, the focus must last until the selection
+/// is set
+fn focus_serial(
+    globals: &GlobalList,
+    event_queue: &mut EventQueue<ClipboardState>,
+    state: &mut ClipboardState,
+) -> Result<(u32, FocusSurface), HaruhiError> {
+    let qh = event_queue.handle();
+    let compositor = globals.bind::<WlCompositor, _, _>(&qh, 1..=4, ())?;
+    // without it there is no way to take the focus
+    let layer_shell = globals
+        .bind::<ZwlrLayerShellV1, _, _>(&qh, 1..=4, ())
+        .map_err(|_| HaruhiError::ClipboardNotSupported)?;
+    let shm = globals.bind::<WlShm, _, _>(&qh, 1..=1, ())?;
+
+    let size = Size {
+        width: 1,
+        height: 1,
+    };
+    let file = File::from(create_shm_fd().map_err(HaruhiError::CreateShmFdFailed)?);
+    set_len(&file, 4)?;
+    let pool = shm.create_pool(file.as_fd(), 4, &qh, ());
+    let buffer = create_buffer(&pool, &qh, 0, size, wl_shm::Format::Argb8888);
+    let surface = compositor.create_surface(&qh, ());
+    let layer_surface = layer_shell.get_layer_surface(
+        &surface,
+        None,
+        Layer::Overlay,
+        "haruhishot-clipboard".to_string(),
+        &qh,
+        (surface.clone(), buffer.clone()),
+    );
+    layer_surface.set_size(size.width, size.height);
+    layer_surface.set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
+    surface.commit();
+
+    let focus = FocusSurface {
+        layer_surface,
+        surface,
+        buffer,
+        pool,
+    };
+    let limit = DispatchLimit::new(Some(FOCUS_TIMEOUT), None);
+    loop {
+        if let Some(serial) = state.focus_serial {
+            return Ok((serial, focus));
+        }
+        if let Err(error) = dispatch_with_limit(event_queue, state, &limit) {
+            focus.destroy();
+            return Err(error);
+        }
+    }
+}
+
+/// The objects of the data control protocol the compositor has
+enum DataControl {
+    Ext {
+        manager: ExtDataControlManagerV1,
+        device: ExtDataControlDeviceV1,
+        source: ExtDataControlSourceV1,
+    },
+    Wlr {
+        manager: ZwlrDataControlManagerV1,
+        device: ZwlrDataControlDeviceV1,
+        source: ZwlrDataControlSourceV1,
+    },
+    /// wl_data_device_manager has no destructor
+    Core {
+        device: WlDataDevice,
+        source: WlDataSource,
+    },
+}
+
+impl DataControl {
+    fn destroy(self) {
+        match self {
+            DataControl::Ext {
+                manager,
+                device,
+                source,
+            } => {
+                source.destroy();
+                device.destroy();
+                manager.destroy();
+            }
+            DataControl::Wlr {
+                manager,
+                device,
+                source,
+            } => {
+                source.destroy();
+                device.destroy();
+                manager.destroy();
+            }
+            DataControl::Core { device, source } => {
+                source.destroy();
+                if device.version() >= 2 {
+                    device.release();
+                }
+            }
+        }
+    }
+}
+
+struct ClipboardState {
+    content: ClipboardContent,
+    /// another client owns the clipboard now
+    cancelled: bool,
+    keyboard: Option<WlKeyboard>,
+    /// the serial of the keyboard focus, only used with wl_data_device
+    focus_serial: Option<u32>,
+}
+
+impl ClipboardState {
+    fn send(&self, mime_type: &str, fd: OwnedFd) {
+        let Some(data) = self.content.data(mime_type) else {
+            debug!("Asked for {mime_type}, which is not offered");
+            return;
+        };
+        // the client may close it before reading everything, it is not our problem
+        if let Err(error) = File::from(fd).write_all(data) {
+            debug!("Failed to send {mime_type}: {error}");
+        }
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for ClipboardState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &wl_registry::WlRegistry,
+        _event: <wl_registry::WlRegistry as Proxy>::Event,
+        _data: &GlobalListContents,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtDataControlSourceV1, ()> for ClipboardState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtDataControlSourceV1,
+        event: <ExtDataControlSourceV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_data_control_source_v1::Event::Send { mime_type, fd } => state.send(&mime_type, fd),
+            ext_data_control_source_v1::Event::Cancelled => state.cancelled = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrDataControlSourceV1, ()> for ClipboardState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrDataControlSourceV1,
+        event: <ZwlrDataControlSourceV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_data_control_source_v1::Event::Send { mime_type, fd } => {
+                state.send(&mime_type, fd)
+            }
+            zwlr_data_control_source_v1::Event::Cancelled => state.cancelled = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtDataControlDeviceV1, ()> for ClipboardState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ExtDataControlDeviceV1,
+        event: <ExtDataControlDeviceV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        // the offers of the current selection are not read, only destroyed
+        match event {
+            ext_data_control_device_v1::Event::Selection { id: Some(offer) }
+            | ext_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                offer.destroy()
+            }
+            ext_data_control_device_v1::Event::Finished => state.cancelled = true,
+            _ => {}
+        }
+    }
+
+    event_created_child!(ClipboardState, ExtDataControlDeviceV1, [
+        ext_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ExtDataControlOfferV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrDataControlDeviceV1, ()> for ClipboardState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrDataControlDeviceV1,
+        event: <ZwlrDataControlDeviceV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_data_control_device_v1::Event::Selection { id: Some(offer) }
+            | zwlr_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                offer.destroy()
+            }
+            zwlr_data_control_device_v1::Event::Finished => state.cancelled = true,
+            _ => {}
+        }
+    }
+
+    event_created_child!(ClipboardState, ZwlrDataControlDeviceV1, [
+        zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+    ]);
+}
+
+impl Dispatch<WlDataSource, ()> for ClipboardState {
+    fn event(
+        state: &mut Self,
+        _proxy: &WlDataSource,
+        event: <WlDataSource as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_data_source::Event::Send { mime_type, fd } => state.send(&mime_type, fd),
+            wl_data_source::Event::Cancelled => state.cancelled = true,
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlDataDevice, ()> for ClipboardState {
+    fn event(
+        _state: &mut Self,
+        _proxy: &WlDataDevice,
+        event: <WlDataDevice as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wl_data_device::Event::Selection { id: Some(offer) } = event {
+            offer.destroy();
+        }
+    }
+
+    event_created_child!(ClipboardState, WlDataDevice, [
+        wl_data_device::EVT_DATA_OFFER_OPCODE => (WlDataOffer, ()),
+    ]);
+}
+
+impl Dispatch<WlSeat, ()> for ClipboardState {
+    fn event(
+        state: &mut Self,
+        seat: &WlSeat,
+        event: <WlSeat as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_seat::Event::Capabilities {
+            capabilities: WEnum::Value(capabilities),
+        } = event
+            && capabilities.contains(wl_seat::Capability::Keyboard)
+            && state.keyboard.is_none()
+        {
+            state.keyboard = Some(seat.get_keyboard(qh, ()));
+        }
+    }
+}
+
+impl Dispatch<WlKeyboard, ()> for ClipboardState {
+    fn event(
+        state: &mut Self,
+        _proxy: &WlKeyboard,
+        event: <WlKeyboard as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wl_keyboard::Event::Enter { serial, .. } = event {
+            state.focus_serial = Some(serial);
+        }
+    }
+}
+
+impl Dispatch<ZwlrLayerSurfaceV1, (WlSurface, WlBuffer)> for ClipboardState {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwlrLayerSurfaceV1,
+        event: <ZwlrLayerSurfaceV1 as Proxy>::Event,
+        (surface, buffer): &(WlSurface, WlBuffer),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let zwlr_layer_surface_v1::Event::Configure { serial, .. } = event {
+            proxy.ack_configure(serial);
+            surface.attach(Some(buffer), 0, 0);
+            surface.commit();
+        }
+    }
+}
+
+delegate_noop!(ClipboardState: ignore WlDataOffer);
+delegate_noop!(ClipboardState: WlDataDeviceManager);
+delegate_noop!(ClipboardState: ignore WlCompositor);
+delegate_noop!(ClipboardState: ignore WlSurface);
+delegate_noop!(ClipboardState: ignore WlShm);
+delegate_noop!(ClipboardState: ignore WlShmPool);
+delegate_noop!(ClipboardState: ignore WlBuffer);
+delegate_noop!(ClipboardState: ignore ZwlrLayerShellV1);
+delegate_noop!(ClipboardState: ExtDataControlManagerV1);
+delegate_noop!(ClipboardState: ignore ExtDataControlOfferV1);
+delegate_noop!(ClipboardState: ZwlrDataControlManagerV1);
+delegate_noop!(ClipboardState: ignore ZwlrDataControlOfferV1);
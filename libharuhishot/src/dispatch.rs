@@ -0,0 +1,208 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+use std::time::{Duration, Instant};
+
+use nix::{
+    errno::Errno,
+    poll::{PollFd, PollFlags, PollTimeout, poll},
+};
+use wayland_client::{
+    Connection, Dispatch, DispatchError, EventQueue, backend::WaylandError,
+    protocol::wl_callback::WlCallback,
+};
+
+use crate::{haruhierror::HaruhiError, utils::CancelToken};
+
+/// How often the cancel token is checked while nothing comes from the compositor
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The limits of waiting for the compositor during one capture
+#[derive(Debug, Clone)]
+pub(crate) struct DispatchLimit {
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    cancel_token: Option<CancelToken>,
+}
+
+impl DispatchLimit {
+    pub(crate) fn new(timeout: Option<Duration>, cancel_token: Option<CancelToken>) -> Self {
+        Self {
+            timeout,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            cancel_token,
+        }
+    }
+
+    fn check(&self) -> Result<(), HaruhiError> {
+        if self
+            .cancel_token
+            .as_ref()
+            .is_some_and(|token| token.is_cancelled())
+        {
+            return Err(HaruhiError::Cancelled);
+        }
+        if let (Some(timeout), Some(deadline)) = (self.timeout, self.deadline)
+            && Instant::now() >= deadline
+        {
+            return Err(HaruhiError::Timeout(timeout));
+        }
+        Ok(())
+    }
+
+    /// How long the next poll is allowed to block, at most until `wake` when it is given
+    fn poll_timeout(&self, wake: Option<Instant>) -> PollTimeout {
+        let now = Instant::now();
+        let mut wait = self
+            .deadline
+            .into_iter()
+            .chain(wake)
+            .min()
+            .map(|until| until.saturating_duration_since(now));
+        if self.cancel_token.is_some() {
+            wait = Some(wait.map_or(CANCEL_CHECK_INTERVAL, |wait| {
+                wait.min(CANCEL_CHECK_INTERVAL)
+            }));
+        }
+        match wait {
+            Some(wait) => PollTimeout::try_from(wait).unwrap_or(PollTimeout::MAX),
+            None => PollTimeout::NONE,
+        }
+    }
+}
+
+/// Like [EventQueue::blocking_dispatch], but poll the connection with the deadline and the cancel
+/// token of the limit, so a stalled compositor cannot block forever.
+pub(crate) fn dispatch_with_limit<D>(
+    event_queue: &mut EventQueue<D>,
+    state: &mut D,
+    limit: &DispatchLimit,
+) -> Result<usize, HaruhiError> {
+    dispatch_until(event_queue, state, limit, None)
+}
+
+/// Like [dispatch_with_limit], but it also returns 0 at `wake`, like for the key repeat
+pub(crate) fn dispatch_until<D>(
+    event_queue: &mut EventQueue<D>,
+    state: &mut D,
+    limit: &DispatchLimit,
+    wake: Option<Instant>,
+) -> Result<usize, HaruhiError> {
+    limit.check()?;
+    loop {
+        let dispatched = event_queue.dispatch_pending(state)?;
+        if dispatched > 0 {
+            return Ok(dispatched);
+        }
+        if wake.is_some_and(|wake| Instant::now() >= wake) {
+            return Ok(0);
+        }
+        read_with_limit(event_queue, limit, wake)?;
+        limit.check()?;
+    }
+}
+
+/// The user data of the wl_display.sync callback of [roundtrip_with_limit], the state marks it
+/// done
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Synced(Arc<AtomicBool>);
+
+impl Synced {
+    pub(crate) fn set_done(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn is_done(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Like [EventQueue::roundtrip], but with the deadline and the cancel token of the limit
+pub(crate) fn roundtrip_with_limit<D>(
+    connection: &Connection,
+    event_queue: &mut EventQueue<D>,
+    state: &mut D,
+    limit: &DispatchLimit,
+) -> Result<(), HaruhiError>
+where
+    D: Dispatch<WlCallback, Synced> + 'static,
+{
+    let synced = Synced::default();
+    connection
+        .display()
+        .sync(&event_queue.handle(), synced.clone());
+    while !synced.is_done() {
+        dispatch_with_limit(event_queue, state, limit)?;
+    }
+    Ok(())
+}
+
+/// Like [dispatch_with_limit] for two event queues of the same connection, it returns once
+/// either of them dispatched something. It also returns 0 at `wake`, so the caller can do
+/// something on time while waiting for the user
+pub(crate) fn dispatch_both_with_limit<D, E>(
+    event_queue: &mut EventQueue<D>,
+    state: &mut D,
+    other_queue: &mut EventQueue<E>,
+    other_state: &mut E,
+    limit: &DispatchLimit,
+    wake: Option<Instant>,
+) -> Result<usize, HaruhiError> {
+    limit.check()?;
+    loop {
+        let dispatched =
+            event_queue.dispatch_pending(state)? + other_queue.dispatch_pending(other_state)?;
+        if dispatched > 0 {
+            return Ok(dispatched);
+        }
+        if wake.is_some_and(|wake| Instant::now() >= wake) {
+            return Ok(0);
+        }
+        // the read puts the events into the queues of their objects, so the events of the
+        // other queue are read too
+        other_queue
+            .flush()
+            .map_err(|err| HaruhiError::DispatchError(DispatchError::Backend(err)))?;
+        read_with_limit(event_queue, limit, wake)?;
+        limit.check()?;
+    }
+}
+
+/// Flush, and read the events from the connection once it is readable, or give up at the
+/// deadline of the limit or at `wake`
+fn read_with_limit<D>(
+    event_queue: &mut EventQueue<D>,
+    limit: &DispatchLimit,
+    wake: Option<Instant>,
+) -> Result<(), HaruhiError> {
+    event_queue
+        .flush()
+        .map_err(|err| HaruhiError::DispatchError(DispatchError::Backend(err)))?;
+
+    let Some(guard) = event_queue.prepare_read() else {
+        return Ok(());
+    };
+    let readable = {
+        let mut fds = [PollFd::new(guard.connection_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, limit.poll_timeout(wake)) {
+            Ok(count) => count > 0,
+            Err(Errno::EINTR) => false,
+            Err(errno) => {
+                return Err(HaruhiError::DispatchError(DispatchError::Backend(
+                    WaylandError::Io(errno.into()),
+                )));
+            }
+        }
+    };
+    if readable {
+        match guard.read() {
+            Ok(_) => {}
+            Err(WaylandError::Io(err)) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(err) => {
+                return Err(HaruhiError::DispatchError(DispatchError::Backend(err)));
+            }
+        }
+    }
+    Ok(())
+}
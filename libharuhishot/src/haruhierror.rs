@@ -1,8 +1,9 @@
-use std::io;
+use std::{io, time::Duration};
 use thiserror::Error;
 use wayland_client::{
     ConnectError, DispatchError,
     globals::{BindError, GlobalError},
+    protocol::wl_shm,
 };
 
 /// This describe the error happens during screenshot
@@ -18,8 +19,40 @@ pub enum HaruhiError {
     BindError(#[from] BindError),
     #[error("Error in write image in shm")]
     ShmError(#[from] io::Error),
+    #[error("Failed to create the shm file")]
+    CreateShmFdFailed(#[source] io::Error),
+    #[error("Failed to resize the shm file to {size} bytes")]
+    ShmResizeFailed {
+        size: u64,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to map the shm file")]
+    MmapFailed(#[source] io::Error),
     #[error("Not Support format")]
     NotSupportFormat,
+    #[error("No converter for the format {0:?}")]
+    NoConverter(wl_shm::Format),
+    #[error("The compositor did not send the buffer size")]
+    MissingBufferSize,
+    #[error("The compositor does not support toplevel capture")]
+    ToplevelCaptureNotSupported,
+    #[error("The toplevel {0} is closed")]
+    ToplevelClosed(String),
+    #[error("The event queue is used by another capture")]
+    EventQueueInUse,
     #[error("Capture Failed")]
     CaptureFailed(String),
+    #[error("Capture timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("Capture cancelled")]
+    Cancelled,
+    #[error("No toplevel to choose")]
+    NoToplevel,
+    #[error("No output to show the chooser on")]
+    NoOutput,
+    #[error("Cannot find the output under the pointer")]
+    PointerOutputNotFound,
+    #[error("The compositor does not support data control, which the clipboard needs")]
+    ClipboardNotSupported,
 }
@@ -0,0 +1,155 @@
+use std::{ffi::c_char, fs::File, os::fd::OwnedFd};
+
+use memmap2::MmapOptions;
+use xkbcommon_dl::{
+    XKB_MOD_NAME_CTRL, XKB_MOD_NAME_SHIFT, XkbCommon, xkb_context, xkb_context_flags, xkb_keymap,
+    xkb_keymap_compile_flags, xkb_keymap_format, xkb_state, xkb_state_component, xkbcommon_option,
+};
+
+/// wl_keyboard sends the linux input event codes, xkb keycodes are 8 more
+const XKB_KEYCODE_OFFSET: u32 = 8;
+
+/// The keymap of the seat compiled by libxkbcommon, and the state of its modifiers. The
+/// pointers are never null, and they are owned by it until it is dropped
+pub(crate) struct Keymap {
+    xkb: &'static XkbCommon,
+    context: *mut xkb_context,
+    keymap: *mut xkb_keymap,
+    state: *mut xkb_state,
+}
+
+impl std::fmt::Debug for Keymap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Keymap")
+    }
+}
+
+impl Keymap {
+    /// Compile the keymap sent with wl_keyboard.keymap. None when libxkbcommon cannot be
+    /// loaded, or the keymap cannot be read
+    pub(crate) fn new(fd: OwnedFd, size: u32) -> Option<Self> {
+        let xkb = xkbcommon_option()?;
+        let file = File::from(fd);
+        // since wl_keyboard version 7 it must be mapped private
+        // SAFETY: the compositor does not change the keymap it sent, and the private read only
+        // mapping cannot be changed from here
+        let map = unsafe {
+            MmapOptions::new()
+                .len(size as usize)
+                .map_copy_read_only(&file)
+        }
+        .ok()?;
+        // the keymap ends with a nul byte
+        let len = map.iter().position(|byte| *byte == 0).unwrap_or(map.len());
+        // SAFETY: the buffer is valid for `len` bytes while it is compiled, libxkbcommon does
+        // not keep it. Every object is checked for null before it is used, and unreferenced
+        // when a later one fails
+        unsafe {
+            let context = (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_FLAGS);
+            if context.is_null() {
+                return None;
+            }
+            let keymap = (xkb.xkb_keymap_new_from_buffer)(
+                context,
+                map.as_ptr() as *const c_char,
+                len,
+                xkb_keymap_format::XKB_KEYMAP_FORMAT_TEXT_V1,
+                xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS,
+            );
+            if keymap.is_null() {
+                (xkb.xkb_context_unref)(context);
+                return None;
+            }
+            let state = (xkb.xkb_state_new)(keymap);
+            if state.is_null() {
+                (xkb.xkb_keymap_unref)(keymap);
+                (xkb.xkb_context_unref)(context);
+                return None;
+            }
+            Some(Self {
+                xkb,
+                context,
+                keymap,
+                state,
+            })
+        }
+    }
+
+    /// Apply wl_keyboard.modifiers
+    pub(crate) fn update_modifiers(
+        &mut self,
+        depressed: u32,
+        latched: u32,
+        locked: u32,
+        group: u32,
+    ) {
+        // SAFETY: the state is not null while self lives
+        unsafe {
+            (self.xkb.xkb_state_update_mask)(self.state, depressed, latched, locked, 0, 0, group);
+        }
+    }
+
+    pub(crate) fn shift(&self) -> bool {
+        self.modifier_active(XKB_MOD_NAME_SHIFT)
+    }
+
+    pub(crate) fn ctrl(&self) -> bool {
+        self.modifier_active(XKB_MOD_NAME_CTRL)
+    }
+
+    /// The name must end with a nul byte, like the `XKB_MOD_NAME_*` constants
+    fn modifier_active(&self, name: &[u8]) -> bool {
+        debug_assert_eq!(name.last(), Some(&0));
+        // SAFETY: the state is not null while self lives, and the name ends with a nul byte
+        unsafe {
+            (self.xkb.xkb_state_mod_name_is_active)(
+                self.state,
+                name.as_ptr() as *const c_char,
+                xkb_state_component::XKB_STATE_MODS_EFFECTIVE,
+            ) > 0
+        }
+    }
+
+    /// The character the key types with the layout and the modifiers, None when it types
+    /// nothing or more than one character
+    pub(crate) fn key_char(&self, code: u32) -> Option<char> {
+        let mut buffer = [0u8; 16];
+        // SAFETY: the state is not null while self lives, and libxkbcommon writes at most
+        // `buffer.len()` bytes with the nul byte
+        let len = unsafe {
+            (self.xkb.xkb_state_key_get_utf8)(
+                self.state,
+                code + XKB_KEYCODE_OFFSET,
+                buffer.as_mut_ptr() as *mut c_char,
+                buffer.len(),
+            )
+        };
+        // the length without the nul byte, it is truncated when it is not less than the buffer
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len < buffer.len())?;
+        let mut chars = std::str::from_utf8(&buffer[..len]).ok()?.chars();
+        match (chars.next(), chars.next()) {
+            (Some(char), None) => Some(char),
+            _ => None,
+        }
+    }
+
+    /// The key repeats while it is held, the modifiers do not
+    pub(crate) fn repeats(&self, code: u32) -> bool {
+        // SAFETY: the keymap is not null while self lives
+        unsafe { (self.xkb.xkb_keymap_key_repeats)(self.keymap, code + XKB_KEYCODE_OFFSET) != 0 }
+    }
+}
+
+impl Drop for Keymap {
+    fn drop(&mut self) {
+        // SAFETY: each of them holds one reference from new, which is given back once. The
+        // state references the keymap, and the keymap the context, so they go in this order
+        unsafe {
+            (self.xkb.xkb_state_unref)(self.state);
+            (self.xkb.xkb_keymap_unref)(self.keymap);
+            (self.xkb.xkb_context_unref)(self.context);
+        }
+    }
+}
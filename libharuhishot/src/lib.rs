@@ -1,14 +1,26 @@
+mod bufferpool;
+mod canvas;
+mod capabilities;
+mod chooser;
+mod clipboard;
 mod convert;
+mod dispatch;
 mod haruhierror;
+mod keymap;
 mod overlay;
+mod pointer;
 mod screenshot;
+mod selector;
 mod state;
 mod utils;
 
+pub use capabilities::{Capabilities, Feature, Protocol};
+pub use clipboard::ClipboardContent;
 pub use screenshot::{
-    AreaSelectCallback, CaptureOption, ClipImageViewInfo, ClipImageViewInfoArea, ImageInfo,
-    ImageViewInfo,
+    AreaSelectCallback, CaptureOption, ClipImageViewInfo, ClipImageViewInfoArea, FrozenFrame,
+    ImageInfo, ImageViewInfo,
 };
+pub use selector::{RegionSelector, SelectionMode};
 pub use state::*;
 pub use utils::*;
 
@@ -19,6 +31,8 @@ pub use haruhierror::HaruhiError as Error;
 /// for user to read the state, report some object
 pub mod reexport {
     pub mod wl_output {
+        /// reexport wl_output Mode flags
+        pub use wayland_client::protocol::wl_output::Mode;
         /// rexport wl_output Transform
         pub use wayland_client::protocol::wl_output::Transform;
         pub use wayland_client::protocol::wl_output::WlOutput;
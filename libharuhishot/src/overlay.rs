@@ -1,6 +1,16 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
-use wayland_client::{Connection, QueueHandle, delegate_noop, protocol::wl_output::WlOutput};
+use wayland_client::{
+    Connection, Proxy, QueueHandle, WEnum, delegate_noop,
+    protocol::{
+        wl_callback::{self, WlCallback},
+        wl_keyboard::{self, WlKeyboard},
+        wl_output::WlOutput,
+        wl_pointer::{self, WlPointer},
+        wl_seat::{self, WlSeat},
+    },
+};
 use wayland_protocols_wlr::layer_shell::v1::client::zwlr_layer_surface_v1::{
     self, ZwlrLayerSurfaceV1,
 };
@@ -10,22 +20,200 @@ use wayland_client::protocol::{
     wl_surface::WlSurface,
 };
 
-use wayland_protocols::wp::viewporter::client::{
-    wp_viewport::WpViewport, wp_viewporter::WpViewporter,
+use wayland_protocols::wp::{
+    cursor_shape::v1::client::{
+        wp_cursor_shape_device_v1::{Shape, WpCursorShapeDeviceV1},
+        wp_cursor_shape_manager_v1::WpCursorShapeManagerV1,
+    },
+    viewporter::client::{wp_viewport::WpViewport, wp_viewporter::WpViewporter},
 };
 
 use wayland_protocols_wlr::layer_shell::v1::client::zwlr_layer_shell_v1::ZwlrLayerShellV1;
 
+use crate::{dispatch::Synced, keymap::Keymap};
+
+/// The linux input event codes of the keys the overlay cares about. They do not depend on the
+/// keyboard layout, the typed text comes from the keymap
+pub(crate) mod keycode {
+    pub(crate) const ESC: u32 = 1;
+    pub(crate) const BACKSPACE: u32 = 14;
+    pub(crate) const TAB: u32 = 15;
+    pub(crate) const ENTER: u32 = 28;
+    pub(crate) const X: u32 = 45;
+    pub(crate) const SPACE: u32 = 57;
+    pub(crate) const KP_ENTER: u32 = 96;
+    pub(crate) const UP: u32 = 103;
+    pub(crate) const LEFT: u32 = 105;
+    pub(crate) const RIGHT: u32 = 106;
+    pub(crate) const DOWN: u32 = 108;
+
+    /// Shift, ctrl, alt, super and caps lock, they do not repeat
+    pub(crate) fn is_modifier(code: u32) -> bool {
+        matches!(code, 29 | 42 | 54 | 56 | 58 | 97 | 100 | 125 | 126)
+    }
+
+    /// The digit of a key on the number row or on the keypad, on a US layout
+    pub(crate) fn digit(code: u32) -> Option<char> {
+        let digit = match code {
+            2..=10 => code - 1,
+            11 | 82 => 0,
+            71..=73 => code - 64,
+            75..=77 => code - 71,
+            79..=81 => code - 78,
+            _ => return None,
+        };
+        char::from_digit(digit, 10)
+    }
+}
+
+/// The linux input event code of the left mouse button
+pub(crate) const BTN_LEFT: u32 = 0x110;
+
+/// The key repeat until the compositor sends wl_keyboard.repeat_info, in keys per second
+const DEFAULT_REPEAT_RATE: i32 = 25;
+/// in milliseconds
+const DEFAULT_REPEAT_DELAY: i32 = 600;
+
+/// Input on the overlay surfaces, the positions are surface local
+#[derive(Debug, Clone)]
+pub(crate) enum OverlayInput {
+    PointerMotion {
+        surface: WlSurface,
+        x: f64,
+        y: f64,
+    },
+    PointerButton {
+        surface: WlSurface,
+        x: f64,
+        y: f64,
+        button: u32,
+        pressed: bool,
+    },
+    Key {
+        code: u32,
+        pressed: bool,
+        /// the character the key types, with the layout of the keyboard
+        text: Option<char>,
+    },
+}
+
 #[derive(Debug)]
 pub(crate) struct LayerShellState {
     pub configured_outputs: HashSet<WlOutput>,
+    /// The size of the layer surfaces from the last configure
+    pub configured_sizes: HashMap<WlOutput, (u32, u32)>,
+    /// The compositor closed one of the layer surfaces
+    pub closed: bool,
+    /// Input received since it was last taken
+    pub inputs: Vec<OverlayInput>,
+    pub shift: bool,
+    pub ctrl: bool,
+    cursor_shape_manager: Option<WpCursorShapeManagerV1>,
+    cursor_shape: Shape,
+    pointer: Option<WlPointer>,
+    cursor_shape_device: Option<WpCursorShapeDeviceV1>,
+    keyboard: Option<WlKeyboard>,
+    /// None without libxkbcommon, then the modifiers are guessed
+    keymap: Option<Keymap>,
+    /// keys per second, 0 disables the repeat
+    repeat_rate: i32,
+    /// milliseconds before the repeat starts
+    repeat_delay: i32,
+    /// the held key, and when it is sent again
+    repeating: Option<(u32, Instant)>,
+    pointer_surface: Option<WlSurface>,
+    pointer_position: (f64, f64),
 }
 
 impl LayerShellState {
     pub(crate) fn new() -> Self {
         Self {
             configured_outputs: HashSet::new(),
+            configured_sizes: HashMap::new(),
+            closed: false,
+            inputs: Vec::new(),
+            shift: false,
+            ctrl: false,
+            cursor_shape_manager: None,
+            cursor_shape: Shape::Default,
+            pointer: None,
+            cursor_shape_device: None,
+            keyboard: None,
+            keymap: None,
+            repeat_rate: DEFAULT_REPEAT_RATE,
+            repeat_delay: DEFAULT_REPEAT_DELAY,
+            repeating: None,
+            pointer_surface: None,
+            pointer_position: (0.0, 0.0),
+        }
+    }
+
+    /// Show the cursor shape over the overlay, if the compositor supports cursor shape
+    pub(crate) fn set_cursor_shape(
+        &mut self,
+        manager: Option<WpCursorShapeManagerV1>,
+        shape: Shape,
+    ) {
+        self.cursor_shape_manager = manager;
+        self.cursor_shape = shape;
+    }
+
+    /// When the held key is sent again, to wake up the dispatch for it
+    pub(crate) fn next_repeat(&self) -> Option<Instant> {
+        self.repeating.map(|(_, at)| at)
+    }
+
+    /// Send the held key again once it is due, as a press
+    pub(crate) fn repeat_keys(&mut self) {
+        let Some((code, at)) = self.repeating else {
+            return;
+        };
+        let now = Instant::now();
+        if now < at {
+            return;
         }
+        self.inputs.push(OverlayInput::Key {
+            code,
+            pressed: true,
+            text: self.key_char(code),
+        });
+        let interval = Duration::from_millis(1000 / self.repeat_rate.max(1) as u64);
+        self.repeating = Some((code, now + interval));
+    }
+
+    /// The character the key types. Without libxkbcommon the layout is not known, the digits
+    /// and `x` of a US layout are used
+    fn key_char(&self, code: u32) -> Option<char> {
+        match &self.keymap {
+            Some(keymap) => keymap.key_char(code),
+            None => keycode::digit(code).or((code == keycode::X).then_some('x')),
+        }
+    }
+
+    fn key_repeats(&self, code: u32) -> bool {
+        self.repeat_rate > 0
+            && match &self.keymap {
+                Some(keymap) => keymap.repeats(code),
+                None => !keycode::is_modifier(code),
+            }
+    }
+
+    /// Release the pointer and the keyboard
+    pub(crate) fn release_input(&mut self) {
+        if let Some(device) = self.cursor_shape_device.take() {
+            device.destroy();
+        }
+        if let Some(pointer) = self.pointer.take()
+            && pointer.version() >= 3
+        {
+            pointer.release();
+        }
+        if let Some(keyboard) = self.keyboard.take()
+            && keyboard.version() >= 3
+        {
+            keyboard.release();
+        }
+        self.repeating = None;
     }
 }
 
@@ -37,6 +225,8 @@ delegate_noop!(LayerShellState: ignore ZwlrLayerShellV1);
 delegate_noop!(LayerShellState: ignore WlSurface);
 delegate_noop!(LayerShellState: ignore WpViewport);
 delegate_noop!(LayerShellState: ignore WpViewporter);
+delegate_noop!(LayerShellState: ignore WpCursorShapeManagerV1);
+delegate_noop!(LayerShellState: ignore WpCursorShapeDeviceV1);
 
 impl wayland_client::Dispatch<ZwlrLayerSurfaceV1, WlOutput> for LayerShellState {
     // No need to instrument here, span from lib.rs is automatically used.
@@ -51,18 +241,198 @@ impl wayland_client::Dispatch<ZwlrLayerSurfaceV1, WlOutput> for LayerShellState
         match event {
             zwlr_layer_surface_v1::Event::Configure {
                 serial,
-                width: _,
-                height: _,
+                width,
+                height,
             } => {
                 tracing::debug!("Acking configure");
                 state.configured_outputs.insert(data.clone());
+                state.configured_sizes.insert(data.clone(), (width, height));
 
                 proxy.ack_configure(serial);
                 tracing::trace!("Acked configure");
             }
             zwlr_layer_surface_v1::Event::Closed => {
-                tracing::debug!("Closed")
+                tracing::debug!("Closed");
+                state.closed = true;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl wayland_client::Dispatch<WlCallback, Synced> for LayerShellState {
+    fn event(
+        _state: &mut Self,
+        _callback: &WlCallback,
+        event: <WlCallback as Proxy>::Event,
+        synced: &Synced,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            synced.set_done();
+        }
+    }
+}
+
+impl wayland_client::Dispatch<WlSeat, ()> for LayerShellState {
+    fn event(
+        state: &mut Self,
+        seat: &WlSeat,
+        event: <WlSeat as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        let wl_seat::Event::Capabilities {
+            capabilities: WEnum::Value(capabilities),
+        } = event
+        else {
+            return;
+        };
+        if capabilities.contains(wl_seat::Capability::Pointer) && state.pointer.is_none() {
+            let pointer = seat.get_pointer(qh, ());
+            state.cursor_shape_device = state
+                .cursor_shape_manager
+                .as_ref()
+                .map(|manager| manager.get_pointer(&pointer, qh, ()));
+            state.pointer = Some(pointer);
+        }
+        if capabilities.contains(wl_seat::Capability::Keyboard) && state.keyboard.is_none() {
+            state.keyboard = Some(seat.get_keyboard(qh, ()));
+        }
+    }
+}
+
+impl wayland_client::Dispatch<WlPointer, ()> for LayerShellState {
+    fn event(
+        state: &mut Self,
+        _pointer: &WlPointer,
+        event: <WlPointer as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_pointer::Event::Enter {
+                serial,
+                surface,
+                surface_x,
+                surface_y,
+            } => {
+                if let Some(device) = &state.cursor_shape_device {
+                    device.set_shape(serial, state.cursor_shape);
+                }
+                state.pointer_position = (surface_x, surface_y);
+                state.inputs.push(OverlayInput::PointerMotion {
+                    surface: surface.clone(),
+                    x: surface_x,
+                    y: surface_y,
+                });
+                state.pointer_surface = Some(surface);
+            }
+            wl_pointer::Event::Leave { .. } => {
+                state.pointer_surface = None;
+            }
+            wl_pointer::Event::Motion {
+                surface_x,
+                surface_y,
+                ..
+            } => {
+                state.pointer_position = (surface_x, surface_y);
+                if let Some(surface) = &state.pointer_surface {
+                    state.inputs.push(OverlayInput::PointerMotion {
+                        surface: surface.clone(),
+                        x: surface_x,
+                        y: surface_y,
+                    });
+                }
+            }
+            wl_pointer::Event::Button {
+                button,
+                state: button_state,
+                ..
+            } => {
+                let Some(surface) = &state.pointer_surface else {
+                    return;
+                };
+                let (x, y) = state.pointer_position;
+                state.inputs.push(OverlayInput::PointerButton {
+                    surface: surface.clone(),
+                    x,
+                    y,
+                    button,
+                    pressed: button_state == WEnum::Value(wl_pointer::ButtonState::Pressed),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+impl wayland_client::Dispatch<WlKeyboard, ()> for LayerShellState {
+    fn event(
+        state: &mut Self,
+        _keyboard: &WlKeyboard,
+        event: <WlKeyboard as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_keyboard::Event::Keymap {
+                format: WEnum::Value(wl_keyboard::KeymapFormat::XkbV1),
+                fd,
+                size,
+            } => {
+                state.keymap = Keymap::new(fd, size);
+                if state.keymap.is_none() {
+                    tracing::debug!("No libxkbcommon or invalid keymap, guessing the modifiers");
+                }
+            }
+            wl_keyboard::Event::RepeatInfo { rate, delay } => {
+                state.repeat_rate = rate;
+                state.repeat_delay = delay;
+            }
+            wl_keyboard::Event::Key {
+                key,
+                state: key_state,
+                ..
+            } => {
+                let pressed = key_state == WEnum::Value(wl_keyboard::KeyState::Pressed);
+                state.inputs.push(OverlayInput::Key {
+                    code: key,
+                    pressed,
+                    text: pressed.then(|| state.key_char(key)).flatten(),
+                });
+                if pressed && state.key_repeats(key) {
+                    let delay = Duration::from_millis(state.repeat_delay.max(0) as u64);
+                    state.repeating = Some((key, Instant::now() + delay));
+                } else if state.repeating.is_some_and(|(code, _)| code == key) {
+                    state.repeating = None;
+                }
+            }
+            wl_keyboard::Event::Leave { .. } => {
+                state.repeating = None;
             }
+            wl_keyboard::Event::Modifiers {
+                mods_depressed,
+                mods_latched,
+                mods_locked,
+                group,
+                ..
+            } => match &mut state.keymap {
+                Some(keymap) => {
+                    keymap.update_modifiers(mods_depressed, mods_latched, mods_locked, group);
+                    state.shift = keymap.shift();
+                    state.ctrl = keymap.ctrl();
+                }
+                // the modifier bits of the usual xkb keymaps
+                None => {
+                    state.shift = mods_depressed & 0x1 != 0;
+                    state.ctrl = mods_depressed & 0x4 != 0;
+                }
+            },
             _ => {}
         }
     }
@@ -0,0 +1,184 @@
+use std::time::Duration;
+
+use wayland_client::{
+    EventQueue, Proxy,
+    protocol::{
+        wl_compositor::WlCompositor, wl_output::WlOutput, wl_seat::WlSeat, wl_surface::WlSurface,
+    },
+};
+use wayland_protocols::wp::{
+    cursor_shape::v1::client::{
+        wp_cursor_shape_device_v1::Shape, wp_cursor_shape_manager_v1::WpCursorShapeManagerV1,
+    },
+    viewporter::client::{wp_viewport::WpViewport, wp_viewporter::WpViewporter},
+};
+use wayland_protocols_wlr::layer_shell::v1::client::{
+    zwlr_layer_shell_v1::{Layer, ZwlrLayerShellV1},
+    zwlr_layer_surface_v1::{Anchor, ZwlrLayerSurfaceV1},
+};
+
+use crate::{
+    HaruhiShotState, WlOutputInfo,
+    canvas::Canvas,
+    dispatch::{DispatchLimit, dispatch_with_limit},
+    haruhierror::HaruhiError,
+    overlay::{LayerShellState, OverlayInput},
+};
+
+/// How long to wait for the pointer to enter one of the transparent surfaces
+const POINTER_ENTER_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A transparent surface to find the output under the pointer
+struct PointerSurface {
+    output: WlOutput,
+    surface: WlSurface,
+    layer_surface: ZwlrLayerSurfaceV1,
+    canvas: Option<Canvas>,
+    viewport: Option<WpViewport>,
+}
+
+impl PointerSurface {
+    fn destroy(self) {
+        if let Some(viewport) = self.viewport {
+            viewport.destroy();
+        }
+        self.layer_surface.destroy();
+        self.surface.destroy();
+        if let Some(canvas) = self.canvas {
+            canvas.destroy();
+        }
+    }
+}
+
+impl HaruhiShotState {
+    /// The output the pointer is on
+    ///
+    /// Transparent layer surfaces are mapped on every output for a moment, the one the pointer
+    /// enters tells the output. Nothing is visible, and the user does not need to do anything.
+    /// It fails with [HaruhiError::PointerOutputNotFound] when the compositor does not send the
+    /// pointer enter in time.
+    pub fn output_under_pointer(&mut self) -> Result<WlOutputInfo, HaruhiError> {
+        let mut state = LayerShellState::new();
+        let mut event_queue: EventQueue<LayerShellState> = self.connection().new_event_queue();
+        let qh = event_queue.handle();
+        let globals = self.globals();
+        let compositor = globals.bind::<WlCompositor, _, _>(&qh, 3..=6, ())?;
+        let layer_shell = globals.bind::<ZwlrLayerShellV1, _, _>(&qh, 1..=4, ())?;
+        let viewporter = globals.bind::<WpViewporter, _, _>(&qh, 1..=1, ()).ok();
+        let cursor_shape_manager = globals
+            .bind::<WpCursorShapeManagerV1, _, _>(&qh, 1..=1, ())
+            .ok();
+        state.set_cursor_shape(cursor_shape_manager.clone(), Shape::Default);
+        let seat = globals.bind::<WlSeat, _, _>(&qh, 1..=7, ())?;
+
+        let mut surfaces = Vec::with_capacity(self.outputs().len());
+        for WlOutputInfo { output, .. } in self.outputs().iter() {
+            let surface = compositor.create_surface(&qh, ());
+            let layer_surface = layer_shell.get_layer_surface(
+                &surface,
+                Some(output),
+                Layer::Overlay,
+                "haruhishot-pointer".to_string(),
+                &qh,
+                output.clone(),
+            );
+            layer_surface.set_exclusive_zone(-1);
+            layer_surface.set_anchor(Anchor::all());
+            surface.commit();
+            surfaces.push(PointerSurface {
+                output: output.clone(),
+                surface,
+                layer_surface,
+                canvas: None,
+                viewport: None,
+            });
+        }
+
+        let limit = DispatchLimit::new(Some(POINTER_ENTER_TIMEOUT), None);
+        let result = self.find_pointer_output(
+            &mut event_queue,
+            &mut state,
+            &mut surfaces,
+            viewporter.as_ref(),
+            &limit,
+        );
+
+        for surface in surfaces {
+            surface.destroy();
+        }
+        state.release_input();
+        if seat.version() >= 5 {
+            seat.release();
+        }
+        if let Some(manager) = cursor_shape_manager {
+            manager.destroy();
+        }
+        if let Some(viewporter) = viewporter {
+            viewporter.destroy();
+        }
+        event_queue.roundtrip(&mut state)?;
+
+        let output = result?;
+        self.outputs()
+            .iter()
+            .find(|info| info.wl_output() == &output)
+            .cloned()
+            .ok_or(HaruhiError::PointerOutputNotFound)
+    }
+
+    fn find_pointer_output(
+        &self,
+        event_queue: &mut EventQueue<LayerShellState>,
+        state: &mut LayerShellState,
+        surfaces: &mut [PointerSurface],
+        viewporter: Option<&WpViewporter>,
+        limit: &DispatchLimit,
+    ) -> Result<WlOutput, HaruhiError> {
+        let qh = event_queue.handle();
+        let wait = |event_queue: &mut EventQueue<LayerShellState>, state: &mut LayerShellState| {
+            dispatch_with_limit(event_queue, state, limit).map_err(|err| match err {
+                HaruhiError::Timeout(_) => HaruhiError::PointerOutputNotFound,
+                err => err,
+            })
+        };
+        for pointer_surface in surfaces.iter_mut() {
+            while !state.configured_outputs.contains(&pointer_surface.output) {
+                wait(event_queue, state)?;
+            }
+            let (width, height) = state
+                .configured_sizes
+                .get(&pointer_surface.output)
+                .copied()
+                .unwrap_or_default();
+            // with a viewport a single transparent pixel is enough
+            let mut canvas = match viewporter {
+                Some(viewporter) => {
+                    let viewport = viewporter.get_viewport(&pointer_surface.surface, &qh, ());
+                    viewport.set_destination(width as i32, height as i32);
+                    pointer_surface.viewport = Some(viewport);
+                    Canvas::new(self.shm(), &qh, 1, 1, 1)?
+                }
+                None => Canvas::new(self.shm(), &qh, width.max(1), height.max(1), 1)?,
+            };
+            canvas.fill(0);
+            canvas.present(&pointer_surface.surface);
+            pointer_surface.canvas = Some(canvas);
+        }
+
+        loop {
+            if let Some(entered) = state.inputs.iter().find_map(|input| match input {
+                OverlayInput::PointerMotion { surface, .. } => Some(surface),
+                _ => None,
+            }) && let Some(pointer_surface) = surfaces
+                .iter()
+                .find(|pointer_surface| &pointer_surface.surface == entered)
+            {
+                return Ok(pointer_surface.output.clone());
+            }
+            if state.closed {
+                return Err(HaruhiError::Cancelled);
+            }
+            wait(event_queue, state)?;
+        }
+    }
+}
@@ -1,23 +1,22 @@
-use std::{
-    ops::Deref,
-    os::fd::OwnedFd,
-    sync::{Arc, RwLock},
-};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use crate::{
     ClipRegion, HaruhiShotState, TopLevel, WlOutputInfo,
+    bufferpool::{PoolBufferId, create_buffer},
+    dispatch::{DispatchLimit, dispatch_with_limit, roundtrip_with_limit},
     haruhierror::HaruhiError,
     overlay::LayerShellState,
-    state::{CaptureInfo, CaptureState, FrameInfo},
+    state::{CaptureInfo, FrameInfo},
     utils::{Position, Region, Size},
 };
 use image::ColorType;
-use memmap2::MmapMut;
 use tracing::debug;
 use wayland_client::{
-    EventQueue, WEnum,
+    EventQueue,
     protocol::{
-        wl_buffer::WlBuffer,
         wl_compositor::WlCompositor,
         wl_output::{self, WlOutput},
         wl_shm,
@@ -25,8 +24,13 @@ use wayland_client::{
     },
 };
 use wayland_protocols::{
-    ext::image_copy_capture::v1::client::{
-        ext_image_copy_capture_frame_v1::FailureReason, ext_image_copy_capture_manager_v1::Options,
+    ext::{
+        image_capture_source::v1::client::ext_image_capture_source_v1::ExtImageCaptureSourceV1,
+        image_copy_capture::v1::client::{
+            ext_image_copy_capture_frame_v1::ExtImageCopyCaptureFrameV1,
+            ext_image_copy_capture_manager_v1::Options,
+            ext_image_copy_capture_session_v1::ExtImageCopyCaptureSessionV1,
+        },
     },
     wp::viewporter::client::wp_viewporter::WpViewporter,
 };
@@ -36,87 +40,7 @@ use wayland_protocols_wlr::layer_shell::v1::client::{
     zwlr_layer_surface_v1::{Anchor, ZwlrLayerSurfaceV1},
 };
 
-use std::os::fd::{AsFd, AsRawFd};
-use std::{
-    fs::File,
-    time::{SystemTime, UNIX_EPOCH},
-};
-
-use nix::{
-    fcntl,
-    sys::{memfd, mman, stat},
-    unistd,
-};
-
-/// capture_output_frame.
-fn create_shm_fd() -> std::io::Result<OwnedFd> {
-    // Only try memfd on linux and freebsd.
-    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
-    loop {
-        // Create a file that closes on successful execution and seal it's operations.
-        match memfd::memfd_create(
-            c"wayshot",
-            memfd::MFdFlags::MFD_CLOEXEC | memfd::MFdFlags::MFD_ALLOW_SEALING,
-        ) {
-            Ok(fd) => {
-                // This is only an optimization, so ignore errors.
-                // F_SEAL_SRHINK = File cannot be reduced in size.
-                // F_SEAL_SEAL = Prevent further calls to fcntl().
-                let _ = fcntl::fcntl(
-                    fd.as_fd(),
-                    fcntl::F_ADD_SEALS(
-                        fcntl::SealFlag::F_SEAL_SHRINK | fcntl::SealFlag::F_SEAL_SEAL,
-                    ),
-                );
-                return Ok(fd);
-            }
-            Err(nix::errno::Errno::EINTR) => continue,
-            Err(nix::errno::Errno::ENOSYS) => break,
-            Err(errno) => return Err(std::io::Error::from(errno)),
-        }
-    }
-
-    // Fallback to using shm_open.
-    let sys_time = SystemTime::now();
-    let mut mem_file_handle = format!(
-        "/wayshot-{}",
-        sys_time.duration_since(UNIX_EPOCH).unwrap().subsec_nanos()
-    );
-    loop {
-        match mman::shm_open(
-            // O_CREAT = Create file if does not exist.
-            // O_EXCL = Error if create and file exists.
-            // O_RDWR = Open for reading and writing.
-            // O_CLOEXEC = Close on successful execution.
-            // S_IRUSR = Set user read permission bit .
-            // S_IWUSR = Set user write permission bit.
-            mem_file_handle.as_str(),
-            fcntl::OFlag::O_CREAT
-                | fcntl::OFlag::O_EXCL
-                | fcntl::OFlag::O_RDWR
-                | fcntl::OFlag::O_CLOEXEC,
-            stat::Mode::S_IRUSR | stat::Mode::S_IWUSR,
-        ) {
-            Ok(fd) => match mman::shm_unlink(mem_file_handle.as_str()) {
-                Ok(_) => return Ok(fd),
-                Err(errno) => match unistd::close(fd.as_raw_fd()) {
-                    Ok(_) => return Err(std::io::Error::from(errno)),
-                    Err(errno) => return Err(std::io::Error::from(errno)),
-                },
-            },
-            Err(nix::errno::Errno::EEXIST) => {
-                // If a file with that handle exists then change the handle
-                mem_file_handle = format!(
-                    "/wayshot-{}",
-                    sys_time.duration_since(UNIX_EPOCH).unwrap().subsec_nanos()
-                );
-                continue;
-            }
-            Err(nix::errno::Errno::EINTR) => continue,
-            Err(errno) => return Err(std::io::Error::from(errno)),
-        }
-    }
-}
+use std::os::fd::{AsFd, BorrowedFd};
 
 /// The data of the image, for the whole screen
 #[derive(Debug, Clone)]
@@ -128,19 +52,31 @@ pub struct ImageInfo {
     pub transform: wl_output::Transform,
 }
 
-#[allow(unused)]
+/// Where the frame is written to
+#[derive(Debug, Clone, Copy)]
+enum CaptureTarget<'a> {
+    /// The fd given by the user, the shm pool and buffer are destroyed after capture
+    Fd(BorrowedFd<'a>),
+    /// A buffer of the [crate::bufferpool::BufferPool] in the state
+    Pool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CaptureFrameData {
+    /// None when the frame is written to the fd of the user
+    buffer: Option<PoolBufferId>,
+    width: u32,
+    height: u32,
+    frame_format: wl_shm::Format,
+    transform: wl_output::Transform,
+}
+
 #[derive(Debug, Clone)]
 struct CaptureOutputData {
     output: WlOutput,
-    buffer: WlBuffer,
+    frame: CaptureFrameData,
     real_width: u32,
     real_height: u32,
-    width: u32,
-    height: u32,
-    frame_bytes: u32,
-    stride: u32,
-    transform: wl_output::Transform,
-    frame_format: wl_shm::Format,
     screen_position: Position,
 }
 
@@ -157,17 +93,6 @@ impl CaptureOutputData {
     }
 }
 
-#[allow(unused)]
-#[derive(Debug, Clone)]
-struct CaptureTopLevelData {
-    buffer: WlBuffer,
-    width: u32,
-    height: u32,
-    frame_bytes: u32,
-    stride: u32,
-    frame_format: wl_shm::Format,
-    transform: wl_output::Transform,
-}
 /// Image view means what part to use
 /// When use the project, every time you will get a picture of the full screen,
 /// and when you do area screenshot, This lib will also provide you with the view of the selected
@@ -211,6 +136,148 @@ impl From<CaptureOption> for Options {
 
 pub trait AreaSelectCallback {
     fn slurp(self, state: &HaruhiShotState) -> Result<Region, HaruhiError>;
+
+    /// Select the area knowing what is on the screens. [HaruhiShotState::capture_area] calls it
+    /// with the frames captured before the selection, one for each output. By default it
+    /// ignores them and calls [AreaSelectCallback::slurp]
+    fn slurp_with_frames(
+        self,
+        state: &HaruhiShotState,
+        _frames: &[FrozenFrame],
+    ) -> Result<Region, HaruhiError>
+    where
+        Self: Sized,
+    {
+        self.slurp(state)
+    }
+}
+
+/// An output and its frame, captured before selecting the area
+#[derive(Debug, Clone)]
+pub struct FrozenFrame {
+    output: WlOutputInfo,
+    image: ImageInfo,
+    /// the layer surface showing the frame, None when the screens are not frozen
+    freeze_surface: Option<FreezeSurface>,
+    /// the event queue of the freeze surfaces, shared by all the frames
+    freeze_queue: Option<Rc<RefCell<FreezeQueue>>>,
+}
+
+/// The layer surface [HaruhiShotState::capture_area] shows the frozen frame on. It covers the
+/// output, and its viewport makes it the logical size of the output
+#[derive(Debug, Clone)]
+pub(crate) struct FreezeSurface {
+    pub(crate) surface: WlSurface,
+    pub(crate) layer_surface: ZwlrLayerSurfaceV1,
+}
+
+/// The event queue the freeze surfaces were created on. Whoever draws on them has to dispatch
+/// it too, so their configure events are acked
+#[derive(Debug)]
+pub(crate) struct FreezeQueue {
+    pub(crate) event_queue: EventQueue<LayerShellState>,
+    pub(crate) state: LayerShellState,
+}
+
+impl FrozenFrame {
+    pub fn output(&self) -> &WlOutputInfo {
+        &self.output
+    }
+
+    /// The frame as the compositor gave it, before the transform of the output is applied
+    pub fn image(&self) -> &ImageInfo {
+        &self.image
+    }
+
+    /// The output in the global logical coordinates
+    pub fn region(&self) -> Region {
+        Region {
+            position: self.output.position(),
+            size: self.output.logical_size(),
+        }
+    }
+
+    pub(crate) fn freeze_surface(&self) -> Option<&FreezeSurface> {
+        self.freeze_surface.as_ref()
+    }
+
+    pub(crate) fn freeze_queue(&self) -> Option<&RefCell<FreezeQueue>> {
+        self.freeze_queue.as_deref()
+    }
+
+    /// The color as rgba at the position in the global logical coordinates, None when it is not
+    /// on this output
+    pub fn pixel(&self, position: Position) -> Option<[u8; 4]> {
+        let Region {
+            position: origin,
+            size,
+        } = self.region();
+        let Position { x, y } = position - origin;
+        if x < 0 || y < 0 || x >= size.width || y >= size.height {
+            return None;
+        }
+        self.shown_pixel(x as i64, y as i64, size.width as i64, size.height as i64)
+    }
+
+    /// The frame like it is shown on the output, scaled to the size. The pixels are row by row,
+    /// as opaque 0xAARRGGBB
+    pub(crate) fn render(&self, width: u32, height: u32) -> Vec<u32> {
+        (0..height as i64)
+            .flat_map(|y| (0..width as i64).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                self.shown_pixel(x, y, width as i64, height as i64)
+                    .map_or(0xff_00_00_00, |[r, g, b, _]| {
+                        u32::from_be_bytes([u8::MAX, r, g, b])
+                    })
+            })
+            .collect()
+    }
+
+    /// The color as rgba at the position, when the transformed frame is scaled to the size
+    fn shown_pixel(
+        &self,
+        x: i64,
+        y: i64,
+        target_width: i64,
+        target_height: i64,
+    ) -> Option<[u8; 4]> {
+        let ImageInfo {
+            ref data,
+            width,
+            height,
+            color_type,
+            transform,
+        } = self.image;
+        let (width, height) = (width as i64, height as i64);
+        // the size of the frame once it is transformed, like it is on the screen
+        let (shown_width, shown_height) = match transform {
+            wl_output::Transform::_90
+            | wl_output::Transform::_270
+            | wl_output::Transform::Flipped90
+            | wl_output::Transform::Flipped270 => (height, width),
+            _ => (width, height),
+        };
+        let shown_x = x * shown_width / target_width;
+        let shown_y = y * shown_height / target_height;
+        let (frame_x, frame_y) = match transform {
+            wl_output::Transform::_90 => (shown_y, height - 1 - shown_x),
+            wl_output::Transform::_180 => (width - 1 - shown_x, height - 1 - shown_y),
+            wl_output::Transform::_270 => (width - 1 - shown_y, shown_x),
+            wl_output::Transform::Flipped => (shown_x, height - 1 - shown_y),
+            wl_output::Transform::Flipped90 => (width - 1 - shown_y, height - 1 - shown_x),
+            wl_output::Transform::Flipped180 => (width - 1 - shown_x, shown_y),
+            wl_output::Transform::Flipped270 => (shown_y, shown_x),
+            _ => (shown_x, shown_y),
+        };
+        let channels = color_type.bytes_per_pixel() as usize;
+        let offset = (frame_y * width + frame_x) as usize * channels;
+        let pixel = data.get(offset..offset + channels)?;
+        Some(match pixel {
+            [r, g, b, a] => [*r, *g, *b, *a],
+            [r, g, b] => [*r, *g, *b, u8::MAX],
+            _ => return None,
+        })
+    }
 }
 
 impl<F> AreaSelectCallback for F
@@ -226,113 +293,357 @@ impl AreaSelectCallback for Region {
         Ok(self)
     }
 }
-impl HaruhiShotState {
-    fn capture_toplevel_inner<T: AsFd>(
-        &mut self,
-        TopLevel { handle, .. }: TopLevel,
+/// A frame requested from a source, but not copied yet
+struct PendingCapture {
+    source: ExtImageCaptureSourceV1,
+    session: ExtImageCopyCaptureSessionV1,
+    frame: ExtImageCopyCaptureFrameV1,
+    info: Arc<RwLock<FrameInfo>>,
+    capture_info: Arc<RwLock<CaptureInfo>>,
+}
+
+impl PendingCapture {
+    fn new(
+        state: &HaruhiShotState,
+        source: ExtImageCaptureSourceV1,
         option: CaptureOption,
-        fd: T,
-        file: Option<&File>,
-    ) -> Result<CaptureTopLevelData, HaruhiError> {
-        let mut event_queue = self.take_event_queue();
-        let img_manager = self.toplevel_image_manager();
-        let capture_manager = self.image_copy_capture_manager();
-        let qh = self.qhandle();
-
-        let source = img_manager.create_source(&handle, qh, ());
+    ) -> Self {
+        let qh = state.qhandle();
         let info = Arc::new(RwLock::new(FrameInfo::default()));
-        let session = capture_manager.create_session(&source, option.into(), qh, info.clone());
-
+        let session = state.image_copy_capture_manager().create_session(
+            &source,
+            option.into(),
+            qh,
+            info.clone(),
+        );
         let capture_info = CaptureInfo::new();
         let frame = session.create_frame(qh, capture_info.clone());
-        event_queue.blocking_dispatch(self).unwrap();
-        let qh = self.qhandle();
+        Self {
+            source,
+            session,
+            frame,
+            info,
+            capture_info,
+        }
+    }
 
-        let shm = self.shm();
-        let info = info.read().unwrap();
+    fn destroy(self) {
+        self.frame.destroy();
+        self.session.destroy();
+        self.source.destroy();
+    }
+}
 
-        let Size { width, height } = info.size();
-        let WEnum::Value(frame_format) = info.format() else {
-            return Err(HaruhiError::NotSupportFormat);
-        };
-        if !matches!(
-            frame_format,
-            wl_shm::Format::Xbgr2101010
-                | wl_shm::Format::Abgr2101010
-                | wl_shm::Format::Argb8888
-                | wl_shm::Format::Xrgb8888
-                | wl_shm::Format::Xbgr8888
-        ) {
-            return Err(HaruhiError::NotSupportFormat);
+/// How often a [LiveCapture] asks for a new frame at most
+const LIVE_CAPTURE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A toplevel captured again whenever it changes, for the thumbnails of the chooser
+///
+/// The session stays open. Once a frame is ready the next one is requested, and the
+/// compositor makes it ready when the toplevel is damaged.
+pub(crate) struct LiveCapture {
+    capture: PendingCapture,
+    buffer: Option<(PoolBufferId, Size<u32>, wl_shm::Format)>,
+    /// the frame is requested, and not ready yet
+    capturing: bool,
+    /// the next frame is not requested before it
+    next_frame: Instant,
+    stopped: bool,
+}
+
+impl LiveCapture {
+    /// When the next frame is requested, None while waiting for the compositor
+    pub(crate) fn next_frame_due(&self) -> Option<Instant> {
+        (!self.stopped && !self.capturing && self.capture.info.read().unwrap().done())
+            .then_some(self.next_frame)
+    }
+
+    pub(crate) fn destroy(self) {
+        self.capture.destroy();
+    }
+}
+
+impl HaruhiShotState {
+    /// Start capturing the toplevel until the [LiveCapture] is destroyed, the events come
+    /// on the event queue of the state
+    pub(crate) fn live_capture(
+        &self,
+        toplevel: &TopLevel,
+        option: CaptureOption,
+    ) -> Result<LiveCapture, HaruhiError> {
+        let source =
+            self.toplevel_image_manager()?
+                .create_source(toplevel.handle(), self.qhandle(), ());
+        Ok(LiveCapture {
+            capture: PendingCapture::new(self, source, option),
+            buffer: None,
+            capturing: false,
+            next_frame: Instant::now(),
+            stopped: false,
+        })
+    }
+
+    /// Request the next frame when it is due, it returns the image once a frame is ready. After
+    /// an error the capture stops
+    pub(crate) fn poll_live_capture(
+        &mut self,
+        live: &mut LiveCapture,
+    ) -> Result<Option<ImageInfo>, HaruhiError> {
+        if live.stopped {
+            return Ok(None);
         }
-        let frame_bytes = 4 * height * width;
-        let mem_fd = fd.as_fd();
+        self.advance_live_capture(live)
+            .inspect_err(|_| live.stopped = true)
+    }
 
-        if let Some(file) = file {
-            file.set_len(frame_bytes as u64).unwrap();
+    fn advance_live_capture(
+        &mut self,
+        live: &mut LiveCapture,
+    ) -> Result<Option<ImageInfo>, HaruhiError> {
+        let qh = self.qhandle().clone();
+        if live.capturing {
+            let Some(result) = live.capture.capture_info.read().unwrap().result() else {
+                return Ok(None);
+            };
+            let transform = result?;
+            live.capturing = false;
+            live.next_frame = Instant::now() + LIVE_CAPTURE_INTERVAL;
+            let (buffer, Size { width, height }, frame_format) =
+                live.buffer.expect("a captured frame has a buffer");
+            // a frame is only captured once, the next one needs a new object
+            live.capture.frame.destroy();
+            live.capture.capture_info = CaptureInfo::new();
+            live.capture.frame = live
+                .capture
+                .session
+                .create_frame(&qh, live.capture.capture_info.clone());
+            return self
+                .frame_to_image(&CaptureFrameData {
+                    buffer: Some(buffer),
+                    width,
+                    height,
+                    frame_format,
+                    transform,
+                })
+                .map(Some);
         }
 
-        let stride = 4 * width;
+        let info = live.capture.info.read().unwrap();
+        if info.stopped() {
+            return Err(HaruhiError::CaptureFailed("Stopped".to_owned()));
+        }
+        if !info.done() || Instant::now() < live.next_frame {
+            return Ok(None);
+        }
+        let (size, format) = info.constraints()?;
+        drop(info);
+        // the buffer is kept while the toplevel keeps its size
+        let buffer = match live.buffer {
+            Some((buffer, buffer_size, buffer_format))
+                if buffer_size == size && buffer_format == format =>
+            {
+                buffer
+            }
+            _ => {
+                let shm = self.shm().clone();
+                self.buffer_pool_mut().acquire(&shm, &qh, size, format)?
+            }
+        };
+        live.buffer = Some((buffer, size, format));
+        live.capture
+            .frame
+            .attach_buffer(self.buffer_pool().buffer(buffer));
+        live.capture.frame.capture();
+        live.capturing = true;
+        Ok(None)
+    }
 
-        let shm_pool = shm.create_pool(mem_fd, (width * height * 4) as i32, qh, ());
-        let buffer = shm_pool.create_buffer(
-            0,
-            width as i32,
-            height as i32,
-            stride as i32,
-            frame_format,
-            qh,
-            (),
-        );
-        frame.attach_buffer(&buffer);
-        frame.capture();
+    /// Capture one frame of the source into the target, the source is destroyed after it.
+    fn capture_source_inner(
+        &mut self,
+        source: ExtImageCaptureSourceV1,
+        option: CaptureOption,
+        target: CaptureTarget,
+    ) -> Result<CaptureFrameData, HaruhiError> {
+        let mut event_queue = self.take_event_queue()?;
+        let limit = self.dispatch_limit();
+        let capture = PendingCapture::new(self, source, option);
+        let result = self.capture_frame(&mut event_queue, &capture, target, &limit);
+        capture.destroy();
+        self.reset_event_queue(event_queue);
+        result
+    }
 
-        let transform;
+    /// Wait until the compositor sent the buffer constraints of all the sessions
+    fn wait_constraints(
+        &mut self,
+        event_queue: &mut EventQueue<Self>,
+        captures: &[PendingCapture],
+        limit: &DispatchLimit,
+    ) -> Result<(), HaruhiError> {
         loop {
-            event_queue.blocking_dispatch(self)?;
-            let info = capture_info.read().unwrap();
-            match info.state() {
-                CaptureState::Succeeded => {
-                    transform = info.transform();
-                    break;
+            let mut all_done = true;
+            for capture in captures {
+                let info = capture.info.read().unwrap();
+                if info.stopped() {
+                    return Err(HaruhiError::CaptureFailed("Stopped".to_owned()));
                 }
-                CaptureState::Failed(info) => match info {
-                    WEnum::Value(reason) => match reason {
-                        FailureReason::Stopped => {
-                            return Err(HaruhiError::CaptureFailed("Stopped".to_owned()));
-                        }
-
-                        FailureReason::BufferConstraints => {
-                            return Err(HaruhiError::CaptureFailed("BufferConstraints".to_owned()));
-                        }
-                        FailureReason::Unknown | _ => {
-                            return Err(HaruhiError::CaptureFailed("Unknown".to_owned()));
-                        }
-                    },
-                    WEnum::Unknown(code) => {
-                        return Err(HaruhiError::CaptureFailed(format!(
-                            "Unknown reason, code : {code}"
-                        )));
-                    }
-                },
-                CaptureState::Pending => {}
+                all_done &= info.done();
+            }
+            if all_done {
+                return Ok(());
             }
+            dispatch_with_limit(event_queue, self, limit)?;
         }
+    }
 
-        self.reset_event_queue(event_queue);
+    /// Wait until all the frames are ready, and get their transform
+    fn wait_frames(
+        &mut self,
+        event_queue: &mut EventQueue<Self>,
+        captures: &[PendingCapture],
+        limit: &DispatchLimit,
+    ) -> Result<Vec<wl_output::Transform>, HaruhiError> {
+        loop {
+            let mut transforms = Vec::with_capacity(captures.len());
+            for capture in captures {
+                match capture.capture_info.read().unwrap().result() {
+                    Some(transform) => transforms.push(transform?),
+                    None => break,
+                }
+            }
+            if transforms.len() == captures.len() {
+                return Ok(transforms);
+            }
+            dispatch_with_limit(event_queue, self, limit)?;
+        }
+    }
 
-        Ok(CaptureTopLevelData {
-            transform,
+    fn capture_frame(
+        &mut self,
+        event_queue: &mut EventQueue<Self>,
+        capture: &PendingCapture,
+        target: CaptureTarget,
+        limit: &DispatchLimit,
+    ) -> Result<CaptureFrameData, HaruhiError> {
+        let qh = self.qhandle().clone();
+        let captures = std::slice::from_ref(capture);
+        self.wait_constraints(event_queue, captures, limit)?;
+
+        let shm = self.shm().clone();
+        let (size, frame_format) = capture.info.read().unwrap().constraints()?;
+        let Size { width, height } = size;
+
+        let frame = &capture.frame;
+        let (buffer, fd_buffer) = match target {
+            CaptureTarget::Fd(mem_fd) => {
+                let shm_pool = shm.create_pool(mem_fd, (width * height * 4) as i32, &qh, ());
+                let buffer = create_buffer(&shm_pool, &qh, 0, size, frame_format);
+                frame.attach_buffer(&buffer);
+                (None, Some((shm_pool, buffer)))
+            }
+            CaptureTarget::Pool => {
+                let id = self
+                    .buffer_pool_mut()
+                    .acquire(&shm, &qh, size, frame_format)?;
+                frame.attach_buffer(self.buffer_pool().buffer(id));
+                (Some(id), None)
+            }
+        };
+        frame.capture();
+
+        let result = self.wait_frames(event_queue, captures, limit);
+
+        if let Some((shm_pool, buffer)) = fd_buffer {
+            buffer.destroy();
+            shm_pool.destroy();
+        }
+
+        Ok(CaptureFrameData {
             buffer,
             width,
             height,
-            frame_bytes,
-            stride,
             frame_format,
+            transform: result?[0],
         })
     }
 
-    fn capture_output_inner<T: AsFd>(
+    /// Request the frames of all the outputs first, then wait for them, so the compositor
+    /// copies them at the same time
+    fn capture_outputs_with_queue(
+        &mut self,
+        event_queue: &mut EventQueue<Self>,
+        option: CaptureOption,
+        outputs: &[WlOutputInfo],
+    ) -> Result<Vec<CaptureFrameData>, HaruhiError> {
+        let limit = self.dispatch_limit();
+        let captures: Vec<PendingCapture> = outputs
+            .iter()
+            .map(|WlOutputInfo { output, .. }| {
+                let source = self
+                    .output_image_manager()
+                    .create_source(output, self.qhandle(), ());
+                PendingCapture::new(self, source, option)
+            })
+            .collect();
+
+        let result = self.capture_frames(event_queue, &captures, &limit);
+        for capture in captures {
+            capture.destroy();
+        }
+        result
+    }
+
+    fn capture_frames(
+        &mut self,
+        event_queue: &mut EventQueue<Self>,
+        captures: &[PendingCapture],
+        limit: &DispatchLimit,
+    ) -> Result<Vec<CaptureFrameData>, HaruhiError> {
+        let qh = self.qhandle().clone();
+        let shm = self.shm().clone();
+        self.wait_constraints(event_queue, captures, limit)?;
+
+        let mut frames = Vec::with_capacity(captures.len());
+        for capture in captures {
+            let (size, frame_format) = capture.info.read().unwrap().constraints()?;
+            let id = self
+                .buffer_pool_mut()
+                .acquire(&shm, &qh, size, frame_format)?;
+            capture.frame.attach_buffer(self.buffer_pool().buffer(id));
+            capture.frame.capture();
+            frames.push((id, size, frame_format));
+        }
+
+        let transforms = self.wait_frames(event_queue, captures, limit)?;
+        Ok(frames
+            .into_iter()
+            .zip(transforms)
+            .map(
+                |((id, Size { width, height }, frame_format), transform)| CaptureFrameData {
+                    buffer: Some(id),
+                    width,
+                    height,
+                    frame_format,
+                    transform,
+                },
+            )
+            .collect())
+    }
+
+    fn capture_toplevel_inner(
+        &mut self,
+        TopLevel { handle, .. }: TopLevel,
+        option: CaptureOption,
+        target: CaptureTarget,
+    ) -> Result<CaptureFrameData, HaruhiError> {
+        let source = self
+            .toplevel_image_manager()?
+            .create_source(&handle, self.qhandle(), ());
+        self.capture_source_inner(source, option, target)
+    }
+
+    fn capture_output_inner(
         &mut self,
         WlOutputInfo {
             output,
@@ -345,108 +656,46 @@ impl HaruhiShotState {
             ..
         }: WlOutputInfo,
         option: CaptureOption,
-        fd: T,
-        file: Option<&File>,
+        target: CaptureTarget,
     ) -> Result<CaptureOutputData, HaruhiError> {
-        let mut event_queue = self.take_event_queue();
-        let img_manager = self.output_image_manager();
-        let capture_manager = self.image_copy_capture_manager();
-        let qh = self.qhandle();
-
-        let source = img_manager.create_source(&output, qh, ());
-        let info = Arc::new(RwLock::new(FrameInfo::default()));
-        let session = capture_manager.create_session(&source, option.into(), qh, info.clone());
-
-        let capture_info = CaptureInfo::new();
-        let frame = session.create_frame(qh, capture_info.clone());
-        event_queue.blocking_dispatch(self).unwrap();
-        let qh = self.qhandle();
-
-        let shm = self.shm();
-        let info = info.read().unwrap();
-
-        let Size { width, height } = info.size();
-        let WEnum::Value(frame_format) = info.format() else {
-            return Err(HaruhiError::NotSupportFormat);
-        };
-        if !matches!(
-            frame_format,
-            wl_shm::Format::Xbgr2101010
-                | wl_shm::Format::Abgr2101010
-                | wl_shm::Format::Argb8888
-                | wl_shm::Format::Xrgb8888
-                | wl_shm::Format::Xbgr8888
-        ) {
-            return Err(HaruhiError::NotSupportFormat);
-        }
-        let frame_bytes = 4 * height * width;
-        let mem_fd = fd.as_fd();
-
-        if let Some(file) = file {
-            file.set_len(frame_bytes as u64).unwrap();
-        }
-
-        let stride = 4 * width;
-
-        let shm_pool = shm.create_pool(mem_fd, (width * height * 4) as i32, qh, ());
-        let buffer = shm_pool.create_buffer(
-            0,
-            width as i32,
-            height as i32,
-            stride as i32,
-            frame_format,
-            qh,
-            (),
-        );
-        frame.attach_buffer(&buffer);
-        frame.capture();
-
-        let transform;
-        loop {
-            event_queue.blocking_dispatch(self)?;
-            let info = capture_info.read().unwrap();
-            match info.state() {
-                CaptureState::Succeeded => {
-                    transform = info.transform();
-                    break;
-                }
-                CaptureState::Failed(info) => match info {
-                    WEnum::Value(reason) => match reason {
-                        FailureReason::Stopped => {
-                            return Err(HaruhiError::CaptureFailed("Stopped".to_owned()));
-                        }
-
-                        FailureReason::BufferConstraints => {
-                            return Err(HaruhiError::CaptureFailed("BufferConstraints".to_owned()));
-                        }
-                        FailureReason::Unknown | _ => {
-                            return Err(HaruhiError::CaptureFailed("Unknown".to_owned()));
-                        }
-                    },
-                    WEnum::Unknown(code) => {
-                        return Err(HaruhiError::CaptureFailed(format!(
-                            "Unknown reason, code : {code}"
-                        )));
-                    }
-                },
-                CaptureState::Pending => {}
-            }
-        }
-
-        self.reset_event_queue(event_queue);
+        let source = self
+            .output_image_manager()
+            .create_source(&output, self.qhandle(), ());
+        let frame = self.capture_source_inner(source, option, target)?;
 
         Ok(CaptureOutputData {
             output,
+            frame,
+            real_width: real_width as u32,
+            real_height: real_height as u32,
+            screen_position,
+        })
+    }
+
+    /// Copy the pooled frame out and convert the copy. The pooled buffer is left as it is, so
+    /// it can still be shown on the frozen screens
+    fn frame_to_image(&mut self, frame: &CaptureFrameData) -> Result<ImageInfo, HaruhiError> {
+        let CaptureFrameData {
             buffer,
             width,
             height,
-            frame_bytes,
-            stride,
             frame_format,
-            real_width: real_width as u32,
-            real_height: real_height as u32,
             transform,
-            screen_position,
+        } = *frame;
+        let converter = crate::convert::create_converter(frame_format)
+            .ok_or(HaruhiError::NoConverter(frame_format))?;
+        let mut data = self
+            .buffer_pool_mut()
+            .data_mut(buffer.expect("pooled frame should have a buffer"))
+            .to_vec();
+        let color_type = converter.convert_inplace(&mut data);
+
+        Ok(ImageInfo {
+            data,
+            width,
+            height,
+            color_type,
+            transform,
         })
     }
 
@@ -456,7 +705,7 @@ impl HaruhiShotState {
         output: WlOutputInfo,
         file: F,
     ) -> Result<(), HaruhiError> {
-        self.capture_output_inner(output, option, file.as_fd(), None)?;
+        self.capture_output_inner(output, option, CaptureTarget::Fd(file.as_fd()))?;
         Ok(())
     }
 
@@ -466,58 +715,50 @@ impl HaruhiShotState {
         option: CaptureOption,
         output: WlOutputInfo,
     ) -> Result<ImageInfo, HaruhiError> {
-        let mem_fd = create_shm_fd().unwrap();
-        let mem_file = File::from(mem_fd);
-        let CaptureOutputData {
-            width,
-            height,
-            frame_format,
-            transform,
-            ..
-        } = self.capture_output_inner(output, option, mem_file.as_fd(), Some(&mem_file))?;
-
-        let mut frame_mmap = unsafe { MmapMut::map_mut(&mem_file).unwrap() };
+        self.buffer_pool_mut().release_all();
+        let CaptureOutputData { frame, .. } =
+            self.capture_output_inner(output, option, CaptureTarget::Pool)?;
 
-        let converter = crate::convert::create_converter(frame_format).unwrap();
-        let color_type = converter.convert_inplace(&mut frame_mmap);
+        self.frame_to_image(&frame)
+    }
 
-        Ok(ImageInfo {
-            data: frame_mmap.deref().into(),
-            width,
-            height,
-            color_type,
-            transform,
-        })
+    /// Capture the outputs at the same time, the images are in the same order as the outputs
+    pub fn capture_outputs(
+        &mut self,
+        option: CaptureOption,
+        outputs: &[WlOutputInfo],
+    ) -> Result<Vec<ImageInfo>, HaruhiError> {
+        self.buffer_pool_mut().release_all();
+        let mut event_queue = self.take_event_queue()?;
+        let result = self.capture_outputs_with_queue(&mut event_queue, option, outputs);
+        self.reset_event_queue(event_queue);
+        result?
+            .iter()
+            .map(|frame| self.frame_to_image(frame))
+            .collect()
     }
 
-    /// Capture a single output
+    /// Capture a toplevel, fails with [HaruhiError::ToplevelClosed] if it is closed
     pub fn capture_toplevel(
         &mut self,
         option: CaptureOption,
         toplevel: TopLevel,
     ) -> Result<ImageInfo, HaruhiError> {
-        let mem_fd = create_shm_fd().unwrap();
-        let mem_file = File::from(mem_fd);
-        let CaptureTopLevelData {
-            width,
-            height,
-            frame_format,
-            transform,
-            ..
-        } = self.capture_toplevel_inner(toplevel, option, mem_file.as_fd(), Some(&mem_file))?;
-
-        let mut frame_mmap = unsafe { MmapMut::map_mut(&mem_file).unwrap() };
-
-        let converter = crate::convert::create_converter(frame_format).unwrap();
-        let color_type = converter.convert_inplace(&mut frame_mmap);
+        if !self.toplevel_alive(toplevel.handle()) {
+            return Err(HaruhiError::ToplevelClosed(toplevel.id_and_title()));
+        }
+        self.buffer_pool_mut().release_all();
+        let handle = toplevel.handle().clone();
+        let name = toplevel.id_and_title();
+        let frame = match self.capture_toplevel_inner(toplevel, option, CaptureTarget::Pool) {
+            // it may be closed during the capture
+            Err(HaruhiError::CaptureFailed(_)) if !self.toplevel_alive(&handle) => {
+                return Err(HaruhiError::ToplevelClosed(name));
+            }
+            frame => frame?,
+        };
 
-        Ok(ImageInfo {
-            data: frame_mmap.deref().into(),
-            width,
-            height,
-            color_type,
-            transform,
-        })
+        self.frame_to_image(&frame)
     }
 
     /// capture with a area region
@@ -529,115 +770,142 @@ impl HaruhiShotState {
     where
         F: AreaSelectCallback,
     {
+        self.buffer_pool_mut().release_all();
         let outputs = self.outputs().clone();
 
         let mut data_list = vec![];
-        for data in outputs.into_iter() {
-            let mem_fd = create_shm_fd().unwrap();
-            let mem_file = File::from(mem_fd);
-            let data =
-                self.capture_output_inner(data, option, mem_file.as_fd(), Some(&mem_file))?;
-            data_list.push(AreaShotInfo { data, mem_file })
+        for data in outputs.iter().cloned() {
+            let data = self.capture_output_inner(data, option, CaptureTarget::Pool)?;
+            data_list.push(AreaShotInfo { data })
         }
 
+        let limit = self.dispatch_limit();
         let mut state = LayerShellState::new();
         let mut event_queue: EventQueue<LayerShellState> = self.connection().new_event_queue();
         let globals = self.globals();
         let qh = event_queue.handle();
-        let compositor = globals.bind::<WlCompositor, _, _>(&qh, 3..=3, ())?;
-        let layer_shell = globals.bind::<ZwlrLayerShellV1, _, _>(&qh, 1..=1, ())?;
-        let viewporter = globals.bind::<WpViewporter, _, _>(&qh, 1..=1, ())?;
-        let mut layer_shell_surfaces: Vec<(WlSurface, ZwlrLayerSurfaceV1)> =
-            Vec::with_capacity(data_list.len());
-        for AreaShotInfo { data, .. } in data_list.iter() {
-            let CaptureOutputData {
-                output,
-                buffer,
-                real_width,
-                real_height,
-                transform,
-                ..
-            } = data;
-            let surface = compositor.create_surface(&qh, ());
-
-            let layer_surface = layer_shell.get_layer_surface(
-                &surface,
-                Some(output),
-                Layer::Overlay,
-                "wayshot".to_string(),
-                &qh,
-                output.clone(),
-            );
-
-            layer_surface.set_exclusive_zone(-1);
-            layer_surface.set_anchor(Anchor::all());
-            layer_surface.set_margin(0, 0, 0, 0);
-
-            debug!("Committing surface creation changes.");
-            surface.commit();
-
-            debug!("Waiting for layer surface to be configured.");
-            while !state.configured_outputs.contains(output) {
-                event_queue.blocking_dispatch(&mut state)?;
-            }
+        let compositor = globals.bind::<WlCompositor, _, _>(&qh, 3..=3, ());
+        let layer_shell = globals.bind::<ZwlrLayerShellV1, _, _>(&qh, 1..=1, ());
+        let viewporter = globals.bind::<WpViewporter, _, _>(&qh, 1..=1, ());
+        let mut layer_shell_surfaces: Vec<FreezeSurface> = Vec::with_capacity(data_list.len());
+        if let (Ok(compositor), Ok(layer_shell), Ok(viewporter)) =
+            (compositor, layer_shell, viewporter)
+        {
+            for AreaShotInfo { data } in data_list.iter() {
+                let CaptureOutputData {
+                    output,
+                    frame:
+                        CaptureFrameData {
+                            buffer, transform, ..
+                        },
+                    real_width,
+                    real_height,
+                    ..
+                } = data;
+                let surface = compositor.create_surface(&qh, ());
+
+                let layer_surface = layer_shell.get_layer_surface(
+                    &surface,
+                    Some(output),
+                    Layer::Overlay,
+                    "wayshot".to_string(),
+                    &qh,
+                    output.clone(),
+                );
+
+                layer_surface.set_exclusive_zone(-1);
+                layer_surface.set_anchor(Anchor::all());
+                layer_surface.set_margin(0, 0, 0, 0);
 
-            surface.set_buffer_transform(*transform);
-            // surface.set_buffer_scale(output_info.scale());
-            surface.attach(Some(buffer), 0, 0);
+                debug!("Committing surface creation changes.");
+                surface.commit();
 
-            let viewport = viewporter.get_viewport(&surface, &qh, ());
-            viewport.set_destination(*real_width as i32, *real_height as i32);
+                debug!("Waiting for layer surface to be configured.");
+                while !state.configured_outputs.contains(output) {
+                    dispatch_with_limit(&mut event_queue, &mut state, &limit)?;
+                }
 
-            debug!("Committing surface with attached buffer.");
-            surface.commit();
-            layer_shell_surfaces.push((surface, layer_surface));
-            event_queue.blocking_dispatch(&mut state)?;
+                surface.set_buffer_transform(*transform);
+                // surface.set_buffer_scale(output_info.scale());
+                let buffer = buffer.expect("pooled frame should have a buffer");
+                surface.attach(Some(self.buffer_pool().buffer(buffer)), 0, 0);
+
+                let viewport = viewporter.get_viewport(&surface, &qh, ());
+                viewport.set_destination(*real_width as i32, *real_height as i32);
+
+                debug!("Committing surface with attached buffer.");
+                surface.commit();
+                layer_shell_surfaces.push(FreezeSurface {
+                    surface,
+                    layer_surface,
+                });
+                roundtrip_with_limit(self.connection(), &mut event_queue, &mut state, &limit)?;
+            }
+        } else {
+            debug!("No layer shell or viewporter, select the area without freezing the screens.");
         }
 
-        let region_re = callback.slurp(self);
+        // the selector draws on the freeze surfaces, so there is one surface for each output.
+        // It dispatches their queue while it does
+        let freeze_queue = Rc::new(RefCell::new(FreezeQueue { event_queue, state }));
+        let frames = outputs
+            .into_iter()
+            .zip(data_list.iter())
+            .enumerate()
+            .map(|(index, (output, AreaShotInfo { data }))| {
+                Ok(FrozenFrame {
+                    output,
+                    image: self.frame_to_image(&data.frame)?,
+                    freeze_surface: layer_shell_surfaces.get(index).cloned(),
+                    freeze_queue: layer_shell_surfaces
+                        .get(index)
+                        .map(|_| freeze_queue.clone()),
+                })
+            })
+            .collect::<Result<Vec<_>, HaruhiError>>();
+        let region_re = frames.and_then(|frames| {
+            callback
+                .slurp_with_frames(self, &frames)
+                .map(|region| (region, frames))
+        });
 
         debug!("Unmapping and destroying layer shell surfaces.");
-        for (surface, layer_shell_surface) in layer_shell_surfaces.iter() {
+        for FreezeSurface {
+            surface,
+            layer_surface,
+        } in layer_shell_surfaces.iter()
+        {
             surface.attach(None, 0, 0);
             surface.commit(); //unmap surface by committing a null buffer
-            layer_shell_surface.destroy();
+            layer_surface.destroy();
+            surface.destroy();
         }
-        event_queue.roundtrip(&mut state)?;
-        let region = region_re?;
-
-        let shotdatas: Vec<&AreaShotInfo> = data_list
+        // the selection took its time, the capture timeout starts again
+        let FreezeQueue { event_queue, state } = &mut *freeze_queue.borrow_mut();
+        roundtrip_with_limit(
+            self.connection(),
+            event_queue,
+            state,
+            &self.dispatch_limit(),
+        )?;
+        let (region, frames) = region_re?;
+
+        let areas = data_list
             .iter()
-            .filter(|data| data.in_this_screen(region))
-            .collect();
-        let mut areas = vec![];
-        for shotdata in shotdatas {
-            let area = shotdata.clip_area(region).expect("should have");
-            let mut frame_mmap = unsafe { MmapMut::map_mut(&shotdata.mem_file).unwrap() };
-
-            let converter = crate::convert::create_converter(shotdata.data.frame_format).unwrap();
-            let color_type = converter.convert_inplace(&mut frame_mmap);
-
-            areas.push(ClipImageViewInfoArea {
-                info: ImageInfo {
-                    data: frame_mmap.deref().into(),
-                    width: shotdata.data.width,
-                    height: shotdata.data.height,
-                    color_type,
-                    transform: shotdata.data.transform,
-                },
-                region: area,
+            .zip(frames)
+            .filter_map(|(shotdata, FrozenFrame { image, .. })| {
+                Some(ClipImageViewInfoArea {
+                    region: shotdata.clip_area(region)?,
+                    info: image,
+                })
             })
-        }
-        Ok(ClipImageViewInfo {
-            region,
-            areas,
-        })
+            .collect();
+        Ok(ClipImageViewInfo { region, areas })
     }
 }
 
 struct AreaShotInfo {
     data: CaptureOutputData,
-    mem_file: File,
 }
 
 impl AreaShotInfo {
@@ -699,8 +967,7 @@ impl AreaShotInfo {
         let CaptureOutputData {
             real_width,
             real_height,
-            width,
-            height,
+            frame: CaptureFrameData { width, height, .. },
             screen_position,
             ..
         } = self.data;
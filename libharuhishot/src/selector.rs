@@ -0,0 +1,876 @@
+use std::{cell::RefCell, time::Instant};
+
+use wayland_client::{
+    EventQueue, Proxy,
+    protocol::{
+        wl_compositor::WlCompositor,
+        wl_output::{self, WlOutput},
+        wl_seat::WlSeat,
+        wl_surface::WlSurface,
+    },
+};
+use wayland_protocols::wp::cursor_shape::v1::client::{
+    wp_cursor_shape_device_v1::Shape, wp_cursor_shape_manager_v1::WpCursorShapeManagerV1,
+};
+use wayland_protocols_wlr::layer_shell::v1::client::{
+    zwlr_layer_shell_v1::{Layer, ZwlrLayerShellV1},
+    zwlr_layer_surface_v1::{Anchor, KeyboardInteractivity, ZwlrLayerSurfaceV1},
+};
+
+use crate::{
+    HaruhiShotState, WlOutputInfo,
+    canvas::{Canvas, Color, GLYPH_HEIGHT, text_width},
+    dispatch::{DispatchLimit, dispatch_both_with_limit, dispatch_until},
+    haruhierror::HaruhiError,
+    overlay::{BTN_LEFT, LayerShellState, OverlayInput, keycode},
+    screenshot::{AreaSelectCallback, FreezeQueue, FreezeSurface, FrozenFrame},
+    utils::{Position, Region, Size},
+};
+
+const DIM: Color = 0x60_00_00_00;
+const BORDER: Color = 0xff_3d_ae_e9;
+const CROSSHAIR: Color = 0xa0_ff_ff_ff;
+const LABEL_BACKGROUND: Color = 0xc0_00_00_00;
+const LABEL: Color = 0xff_ff_ff_ff;
+const BORDER_WIDTH: i32 = 2;
+const TEXT_SCALE: u32 = 2;
+const LABEL_PADDING: i32 = 4;
+/// The loupe shows this many pixels in each direction
+const LOUPE_CELLS: i32 = 15;
+/// The size of a pixel in the loupe
+const LOUPE_ZOOM: i32 = 8;
+const LOUPE_SIZE: i32 = LOUPE_CELLS * LOUPE_ZOOM + BORDER_WIDTH * 2;
+const LOUPE_SAMPLE_INNER: Color = 0xff_ff_ff_ff;
+const LOUPE_SAMPLE_OUTER: Color = 0xff_00_00_00;
+/// The margin between the pointer and the loupe or the label
+const POINTER_MARGIN: i32 = 12;
+
+/// What [RegionSelector] selects
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelectionMode {
+    /// Drag a rectangle, it can span several outputs. A click without dragging selects the
+    /// output
+    #[default]
+    Area,
+    /// Click a pixel, the region is 1x1
+    Point,
+    /// Click an output, the region is the whole output
+    Output,
+}
+
+/// Select a region with the pointer or the keyboard on layer shell surfaces over every output
+///
+/// It shows the selection rectangle with its size and a crosshair. Escape or the right button
+/// cancels with [HaruhiError::Cancelled]. Used with [HaruhiShotState::capture_area] it draws on
+/// the surfaces showing the frozen screens, it also works alone by calling
+/// [AreaSelectCallback::slurp].
+///
+/// The keyboard works without the pointer:
+///
+/// - the arrows move the crosshair by 1 pixel, or by 10 pixels with shift
+/// - space starts the selection at the crosshair, then the arrows resize it
+/// - tab swaps the corners, so the arrows resize the other edges
+/// - the arrows with ctrl move the whole selection
+/// - typing `WxH`, like `640x480`, gives the size of the selection, backspace corrects it
+/// - enter confirms the selection, or the output or the pixel under the crosshair
+#[derive(Debug, Clone, Copy)]
+pub struct RegionSelector {
+    mode: SelectionMode,
+    loupe: bool,
+    sample_size: u32,
+}
+
+impl Default for RegionSelector {
+    fn default() -> Self {
+        Self {
+            mode: SelectionMode::default(),
+            loupe: false,
+            sample_size: 1,
+        }
+    }
+}
+
+impl RegionSelector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_mode(mut self, mode: SelectionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Show the pixels around the pointer zoomed in. It needs the frames, so it is only shown
+    /// when used with [HaruhiShotState::capture_area]
+    pub fn with_loupe(mut self, loupe: bool) -> Self {
+        self.loupe = loupe;
+        self
+    }
+
+    /// Outline the square of this many pixels around the pointer in the loupe, for callers
+    /// which sample more than one pixel
+    pub fn with_sample_size(mut self, sample_size: u32) -> Self {
+        self.sample_size = sample_size.max(1);
+        self
+    }
+}
+
+impl AreaSelectCallback for RegionSelector {
+    fn slurp(self, state: &HaruhiShotState) -> Result<Region, HaruhiError> {
+        self.select(state, &[])
+    }
+
+    fn slurp_with_frames(
+        self,
+        state: &HaruhiShotState,
+        frames: &[FrozenFrame],
+    ) -> Result<Region, HaruhiError> {
+        self.select(state, frames)
+    }
+}
+
+impl RegionSelector {
+    fn select(
+        self,
+        state: &HaruhiShotState,
+        frames: &[FrozenFrame],
+    ) -> Result<Region, HaruhiError> {
+        let mut layer_state = LayerShellState::new();
+        let mut event_queue: EventQueue<LayerShellState> = state.connection().new_event_queue();
+        let qh = event_queue.handle();
+        let globals = state.globals();
+        let compositor = globals.bind::<WlCompositor, _, _>(&qh, 3..=6, ())?;
+        let layer_shell = globals.bind::<ZwlrLayerShellV1, _, _>(&qh, 1..=4, ())?;
+        let cursor_shape_manager = globals
+            .bind::<WpCursorShapeManagerV1, _, _>(&qh, 1..=1, ())
+            .ok();
+        layer_state.set_cursor_shape(cursor_shape_manager.clone(), Shape::Crosshair);
+        let seat = globals.bind::<WlSeat, _, _>(&qh, 1..=7, ())?;
+
+        let mut surfaces = Vec::with_capacity(state.outputs().len());
+        for info in state.outputs().iter() {
+            // draw on the surface of the frozen screen, so there is one surface for each output
+            if let Some(freeze_surface) = frames
+                .iter()
+                .find(|frame| frame.output().wl_output() == info.wl_output())
+                .and_then(|frame| frame.freeze_surface())
+            {
+                let FreezeSurface {
+                    surface,
+                    layer_surface,
+                } = freeze_surface.clone();
+                layer_surface.set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
+                surface.commit();
+                surfaces.push(SelectorSurface::new(info, surface, layer_surface, true));
+                continue;
+            }
+            let surface = compositor.create_surface(&qh, ());
+            let layer_surface = layer_shell.get_layer_surface(
+                &surface,
+                Some(info.wl_output()),
+                Layer::Overlay,
+                "haruhishot-selector".to_string(),
+                &qh,
+                info.wl_output().clone(),
+            );
+            layer_surface.set_exclusive_zone(-1);
+            layer_surface.set_anchor(Anchor::all());
+            layer_surface.set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
+            surface.commit();
+            surfaces.push(SelectorSurface::new(info, surface, layer_surface, false));
+        }
+
+        let limit = state.interaction_limit();
+        let result = self.run(
+            state,
+            frames,
+            &mut event_queue,
+            &mut layer_state,
+            &mut surfaces,
+            &limit,
+        );
+
+        for surface in surfaces {
+            surface.destroy();
+        }
+        layer_state.release_input();
+        if seat.version() >= 5 {
+            seat.release();
+        }
+        if let Some(manager) = cursor_shape_manager {
+            manager.destroy();
+        }
+        event_queue.roundtrip(&mut layer_state)?;
+        result
+    }
+}
+
+/// Dispatch the selector, and the queue of the freeze surfaces when it draws on them, so their
+/// configure events are acked
+fn dispatch(
+    event_queue: &mut EventQueue<LayerShellState>,
+    layer_state: &mut LayerShellState,
+    freeze_queue: Option<&RefCell<FreezeQueue>>,
+    limit: &DispatchLimit,
+    wake: Option<Instant>,
+) -> Result<usize, HaruhiError> {
+    match freeze_queue {
+        Some(freeze_queue) => {
+            let FreezeQueue {
+                event_queue: freeze_event_queue,
+                state,
+            } = &mut *freeze_queue.borrow_mut();
+            dispatch_both_with_limit(
+                event_queue,
+                layer_state,
+                freeze_event_queue,
+                state,
+                limit,
+                wake,
+            )
+        }
+        None => dispatch_until(event_queue, layer_state, limit, wake),
+    }
+}
+
+/// The selector on one output
+struct SelectorSurface {
+    output: WlOutput,
+    scale: i32,
+    /// the output in the global logical coordinates
+    region: Region,
+    surface: WlSurface,
+    layer_surface: ZwlrLayerSurfaceV1,
+    /// the surface of [HaruhiShotState::capture_area], which destroys it
+    frozen: bool,
+    canvas: Option<Canvas>,
+    /// the last drawing had something besides the dim background
+    drawn_content: bool,
+}
+
+impl SelectorSurface {
+    fn new(
+        info: &WlOutputInfo,
+        surface: WlSurface,
+        layer_surface: ZwlrLayerSurfaceV1,
+        frozen: bool,
+    ) -> Self {
+        Self {
+            output: info.wl_output().clone(),
+            scale: info.scale(),
+            region: Region {
+                position: info.position(),
+                size: info.logical_size(),
+            },
+            surface,
+            layer_surface,
+            frozen,
+            canvas: None,
+            drawn_content: true,
+        }
+    }
+
+    fn intersects(&self, other: Region) -> bool {
+        let Region { position, size } = self.region;
+        other.position.x < position.x + size.width
+            && other.position.y < position.y + size.height
+            && other.position.x + other.size.width > position.x
+            && other.position.y + other.size.height > position.y
+    }
+
+    /// The position is in the output, or on its right or bottom edge when `edge` is set
+    fn reaches(&self, Position { x, y }: Position, edge: bool) -> bool {
+        let Region { position, size } = self.region;
+        let edge = i32::from(edge);
+        x >= position.x
+            && y >= position.y
+            && x < position.x + size.width + edge
+            && y < position.y + size.height + edge
+    }
+
+    /// Draw the selection, the pointer is only given to the surface it is on
+    fn draw(
+        &mut self,
+        pointer: Option<Position>,
+        selection: Option<Region>,
+        label: Option<&str>,
+        loupe: Option<&Loupe>,
+    ) {
+        let has_content =
+            pointer.is_some() || selection.is_some_and(|selection| self.intersects(selection));
+        if !has_content && !self.drawn_content {
+            return;
+        }
+        self.drawn_content = has_content;
+        let origin = self.region.position;
+        let Some(canvas) = self.canvas.as_mut() else {
+            return;
+        };
+        canvas.fill(DIM);
+
+        if let Some(Region { position, size }) = selection {
+            let Position { x, y } = position - origin;
+            canvas.clear_rect(x, y, size.width, size.height);
+            canvas.stroke_rect(
+                x - BORDER_WIDTH,
+                y - BORDER_WIDTH,
+                size.width + BORDER_WIDTH * 2,
+                size.height + BORDER_WIDTH * 2,
+                BORDER_WIDTH,
+                BORDER,
+            );
+        }
+
+        if let Some(pointer) = pointer {
+            let Position { x, y } = pointer - origin;
+            canvas.fill_rect(0, y, canvas.width() as i32, 1, CROSSHAIR);
+            canvas.fill_rect(x, 0, 1, canvas.height() as i32, CROSSHAIR);
+
+            // the loupe and the label under it stay next to the pointer, but inside of the output
+            let loupe_size = if loupe.is_some() { LOUPE_SIZE } else { 0 };
+            let (label_width, label_height) = label.map_or((0, 0), |label| {
+                (
+                    text_width(label, TEXT_SCALE) as i32 + LABEL_PADDING * 2,
+                    (GLYPH_HEIGHT * TEXT_SCALE) as i32 + LABEL_PADDING * 2,
+                )
+            });
+            let width = loupe_size.max(label_width);
+            let height = loupe_size + label_height;
+            let mut box_x = x + POINTER_MARGIN;
+            let mut box_y = y + POINTER_MARGIN;
+            if box_x + width > canvas.width() as i32 {
+                box_x = x - POINTER_MARGIN - width;
+            }
+            if box_y + height > canvas.height() as i32 {
+                box_y = y - POINTER_MARGIN - height;
+            }
+            if let Some(loupe) = loupe {
+                loupe.draw(canvas, box_x, box_y);
+            }
+            if let Some(label) = label {
+                let label_y = box_y + loupe_size;
+                canvas.fill_rect(box_x, label_y, label_width, label_height, LABEL_BACKGROUND);
+                canvas.draw_text(
+                    box_x + LABEL_PADDING,
+                    label_y + LABEL_PADDING,
+                    label,
+                    TEXT_SCALE,
+                    LABEL,
+                );
+            }
+        }
+        canvas.present(&self.surface);
+    }
+
+    fn destroy(self) {
+        self.surface.attach(None, 0, 0);
+        self.surface.commit();
+        if !self.frozen {
+            self.layer_surface.destroy();
+            self.surface.destroy();
+        }
+        if let Some(canvas) = self.canvas {
+            canvas.destroy();
+        }
+    }
+}
+
+/// The pixels around the pointer, read from the frozen frames
+struct Loupe {
+    /// row by row, None outside of the outputs
+    pixels: Vec<Option<Color>>,
+    sample_size: i32,
+}
+
+impl Loupe {
+    fn new(frames: &[FrozenFrame], center: Position, sample_size: u32) -> Self {
+        let radius = LOUPE_CELLS / 2;
+        let pixels = (-radius..=radius)
+            .flat_map(|y| (-radius..=radius).map(move |x| Position { x, y }))
+            .map(|offset| {
+                let position = center + offset;
+                frames
+                    .iter()
+                    .find_map(|frame| frame.pixel(position))
+                    .map(|[r, g, b, _]| {
+                        0xff_00_00_00 | (r as Color) << 16 | (g as Color) << 8 | b as Color
+                    })
+            })
+            .collect();
+        Self {
+            pixels,
+            sample_size: sample_size.min(LOUPE_CELLS as u32) as i32,
+        }
+    }
+
+    /// The color of the pixel under the pointer
+    fn center(&self) -> Option<Color> {
+        self.pixels[self.pixels.len() / 2]
+    }
+
+    fn draw(&self, canvas: &mut Canvas, x: i32, y: i32) {
+        canvas.fill_rect(x, y, LOUPE_SIZE, LOUPE_SIZE, BORDER);
+        let inner_x = x + BORDER_WIDTH;
+        let inner_y = y + BORDER_WIDTH;
+        for (index, pixel) in self.pixels.iter().enumerate() {
+            let column = index as i32 % LOUPE_CELLS;
+            let row = index as i32 / LOUPE_CELLS;
+            canvas.fill_rect(
+                inner_x + column * LOUPE_ZOOM,
+                inner_y + row * LOUPE_ZOOM,
+                LOUPE_ZOOM,
+                LOUPE_ZOOM,
+                pixel.unwrap_or(LOUPE_SAMPLE_OUTER),
+            );
+        }
+        // the sampled square, the pixel under the pointer is in its middle. When the size is
+        // even, it is the bottom right one of the four in the middle
+        let first = LOUPE_CELLS / 2 - self.sample_size / 2;
+        let sample_x = inner_x + first * LOUPE_ZOOM;
+        let sample_y = inner_y + first * LOUPE_ZOOM;
+        let sample_size = self.sample_size * LOUPE_ZOOM;
+        canvas.stroke_rect(
+            sample_x - 1,
+            sample_y - 1,
+            sample_size + 2,
+            sample_size + 2,
+            1,
+            LOUPE_SAMPLE_OUTER,
+        );
+        canvas.stroke_rect(
+            sample_x,
+            sample_y,
+            sample_size,
+            sample_size,
+            1,
+            LOUPE_SAMPLE_INNER,
+        );
+    }
+}
+
+/// The rectangle between two corners
+fn region_between(start: Position, end: Position) -> Region {
+    Region {
+        position: Position {
+            x: start.x.min(end.x),
+            y: start.y.min(end.y),
+        },
+        size: Size {
+            width: (end.x - start.x).abs(),
+            height: (end.y - start.y).abs(),
+        },
+    }
+}
+
+/// The state of the selection, changed by the pointer and the keyboard
+#[derive(Debug, Default)]
+struct Selection {
+    /// the corner where the drag or the keyboard selection started
+    anchor: Option<Position>,
+    /// the pointer, or the position moved to with the arrows
+    cursor: Option<Position>,
+    /// the left button is held down on the overlay
+    dragging: bool,
+    /// the size typed as `WxH`
+    typed: String,
+}
+
+impl Selection {
+    /// The typed size, once both sides are given
+    fn typed_size(&self) -> Option<Size> {
+        let (width, height) = self.typed.split_once('x')?;
+        let width = width.parse().ok().filter(|width| *width > 0)?;
+        let height = height.parse().ok().filter(|height| *height > 0)?;
+        Some(Size { width, height })
+    }
+
+    /// The typed size placed at the anchor, or at the cursor without anchor
+    fn typed_region(&self) -> Option<Region> {
+        Some(Region {
+            position: self.anchor.or(self.cursor)?,
+            size: self.typed_size()?,
+        })
+    }
+
+    fn region(&self) -> Option<Region> {
+        self.typed_region()
+            .or_else(|| Some(region_between(self.anchor?, self.cursor?)))
+    }
+
+    /// Turn the typed size into the corners, so the arrows continue from it
+    fn apply_typed(&mut self) {
+        if let Some(Region { position, size }) = self.typed_region() {
+            self.anchor = Some(position);
+            self.cursor = Some(
+                position
+                    + Position {
+                        x: size.width,
+                        y: size.height,
+                    },
+            );
+        }
+        self.typed.clear();
+    }
+
+    fn type_key(&mut self, code: u32, text: Option<char>) {
+        if code == keycode::BACKSPACE {
+            self.typed.pop();
+            return;
+        }
+        match text {
+            Some('x' | 'X') if !self.typed.is_empty() && !self.typed.contains('x') => {
+                self.typed.push('x');
+            }
+            Some(digit) if digit.is_ascii_digit() && self.typed.len() < MAX_TYPED_LEN => {
+                self.typed.push(digit);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Enough for two sides of five digits
+const MAX_TYPED_LEN: usize = 11;
+
+impl RegionSelector {
+    /// The output shown under the position, the one it is in, or else the one it is on the
+    /// edge of
+    fn surface_at(&self, surfaces: &[SelectorSurface], position: Position) -> Option<usize> {
+        surfaces
+            .iter()
+            .position(|surface| surface.reaches(position, false))
+            .or_else(|| {
+                surfaces
+                    .iter()
+                    .position(|surface| surface.reaches(position, self.mode == SelectionMode::Area))
+            })
+    }
+
+    /// The part of `delta` the position can move while staying on the outputs. In the area mode
+    /// the position is a corner, and it can be on the right or bottom edge of an output
+    fn clamp_move(
+        &self,
+        surfaces: &[SelectorSurface],
+        from: Position,
+        delta: Position,
+    ) -> Position {
+        let edge = self.mode == SelectionMode::Area;
+        let to = from + delta;
+        if surfaces.iter().any(|surface| surface.reaches(to, edge)) {
+            return delta;
+        }
+        let Some(surface) = surfaces.iter().find(|surface| surface.reaches(from, edge)) else {
+            return Position::default();
+        };
+        let Region { position, size } = surface.region;
+        let last = i32::from(!edge);
+        Position {
+            x: to.x.clamp(position.x, position.x + size.width - last) - from.x,
+            y: to.y.clamp(position.y, position.y + size.height - last) - from.y,
+        }
+    }
+
+    /// The region chosen at the cursor, when confirming with enter or releasing the button
+    fn confirm(&self, surfaces: &[SelectorSurface], selection: &Selection) -> Option<Region> {
+        if let Some(region) = selection.typed_region() {
+            return Some(region);
+        }
+        let cursor = selection.cursor?;
+        let output = || {
+            self.surface_at(surfaces, cursor)
+                .map(|index| surfaces[index].region)
+        };
+        match self.mode {
+            SelectionMode::Area => selection
+                .anchor
+                .map(|anchor| region_between(anchor, cursor))
+                .filter(|region| region.size.width > 0 && region.size.height > 0)
+                .or_else(output),
+            SelectionMode::Point => Some(Region {
+                position: cursor,
+                size: Size {
+                    width: 1,
+                    height: 1,
+                },
+            }),
+            SelectionMode::Output => output(),
+        }
+    }
+
+    /// Move the cursor with the arrows, or the whole selection with ctrl
+    fn move_with_arrow(
+        &self,
+        surfaces: &[SelectorSurface],
+        selection: &mut Selection,
+        code: u32,
+        shift: bool,
+        ctrl: bool,
+    ) {
+        let step = if shift { 10 } else { 1 };
+        let delta = match code {
+            keycode::LEFT => Position { x: -step, y: 0 },
+            keycode::RIGHT => Position { x: step, y: 0 },
+            keycode::UP => Position { x: 0, y: -step },
+            _ => Position { x: 0, y: step },
+        };
+        selection.apply_typed();
+        let Some(cursor) = selection.cursor else {
+            return;
+        };
+        let mut delta = self.clamp_move(surfaces, cursor, delta);
+        if ctrl && let Some(anchor) = selection.anchor {
+            // both corners move by the same amount, so the size stays
+            let anchor_delta = self.clamp_move(surfaces, anchor, delta);
+            delta = Position {
+                x: if anchor_delta.x.abs() < delta.x.abs() {
+                    anchor_delta.x
+                } else {
+                    delta.x
+                },
+                y: if anchor_delta.y.abs() < delta.y.abs() {
+                    anchor_delta.y
+                } else {
+                    delta.y
+                },
+            };
+            selection.anchor = Some(anchor + delta);
+        }
+        selection.cursor = Some(cursor + delta);
+    }
+
+    /// Handle a key press, it returns the result when the selection ends
+    fn handle_key(
+        &self,
+        surfaces: &[SelectorSurface],
+        selection: &mut Selection,
+        layer_state: &LayerShellState,
+        code: u32,
+        text: Option<char>,
+    ) -> Option<Result<Region, HaruhiError>> {
+        if code == keycode::ESC {
+            return Some(Err(HaruhiError::Cancelled));
+        }
+        // without the pointer the keyboard starts in the middle of the first output
+        if selection.cursor.is_none() {
+            let Region { position, size } = surfaces.first()?.region;
+            selection.cursor = Some(
+                position
+                    + Position {
+                        x: size.width / 2,
+                        y: size.height / 2,
+                    },
+            );
+        }
+        match code {
+            keycode::ENTER | keycode::KP_ENTER => {
+                return self.confirm(surfaces, selection).map(Ok);
+            }
+            keycode::UP | keycode::DOWN | keycode::LEFT | keycode::RIGHT => self.move_with_arrow(
+                surfaces,
+                selection,
+                code,
+                layer_state.shift,
+                layer_state.ctrl,
+            ),
+            _ if self.mode != SelectionMode::Area => {}
+            keycode::SPACE => {
+                selection.typed.clear();
+                selection.anchor = match selection.anchor {
+                    Some(_) => None,
+                    None => selection.cursor,
+                };
+            }
+            keycode::TAB => {
+                selection.apply_typed();
+                if let Some(anchor) = selection.anchor {
+                    selection.anchor = selection.cursor;
+                    selection.cursor = Some(anchor);
+                }
+            }
+            code => selection.type_key(code, text),
+        }
+        None
+    }
+
+    fn run(
+        &self,
+        state: &HaruhiShotState,
+        frames: &[FrozenFrame],
+        event_queue: &mut EventQueue<LayerShellState>,
+        layer_state: &mut LayerShellState,
+        surfaces: &mut [SelectorSurface],
+        limit: &DispatchLimit,
+    ) -> Result<Region, HaruhiError> {
+        let qh = event_queue.handle();
+        let freeze_queue = frames.iter().find_map(FrozenFrame::freeze_queue);
+        for selector_surface in surfaces.iter_mut() {
+            let frame = frames
+                .iter()
+                .find(|frame| frame.output().wl_output() == &selector_surface.output)
+                .filter(|_| selector_surface.frozen);
+            // the freeze surface is configured already, and its viewport makes it the logical
+            // size of the output
+            let (width, height) = match frame {
+                Some(_) => (
+                    selector_surface.region.size.width as u32,
+                    selector_surface.region.size.height as u32,
+                ),
+                None => {
+                    while !layer_state
+                        .configured_outputs
+                        .contains(&selector_surface.output)
+                    {
+                        if layer_state.closed {
+                            return Err(HaruhiError::Cancelled);
+                        }
+                        dispatch(event_queue, layer_state, freeze_queue, limit, None)?;
+                    }
+                    layer_state
+                        .configured_sizes
+                        .get(&selector_surface.output)
+                        .copied()
+                        .unwrap_or_default()
+                }
+            };
+            if width > 0 && height > 0 {
+                let mut canvas =
+                    Canvas::new(state.shm(), &qh, width, height, selector_surface.scale)?;
+                if let Some(frame) = frame {
+                    // the frame is drawn under the selection, already transformed
+                    let (buffer_width, buffer_height) = canvas.buffer_size();
+                    canvas.set_background(frame.render(buffer_width, buffer_height));
+                    selector_surface
+                        .surface
+                        .set_buffer_transform(wl_output::Transform::Normal);
+                }
+                selector_surface.canvas = Some(canvas);
+            }
+        }
+
+        let mut selection = Selection::default();
+        let mut redraw = true;
+        loop {
+            if layer_state.closed {
+                return Err(HaruhiError::Cancelled);
+            }
+            if redraw {
+                let cursor = selection.cursor;
+                let region = match (self.mode, cursor) {
+                    (SelectionMode::Area, _) => selection.region(),
+                    (SelectionMode::Output, Some(cursor)) => self
+                        .surface_at(surfaces, cursor)
+                        .map(|index| surfaces[index].region),
+                    _ => None,
+                };
+                let loupe = cursor
+                    .filter(|_| self.loupe && !frames.is_empty())
+                    .map(|cursor| Loupe::new(frames, cursor, self.sample_size));
+                let label = match (self.mode, region, cursor) {
+                    (SelectionMode::Point, _, Some(Position { x, y })) => {
+                        Some(match loupe.as_ref().and_then(|loupe| loupe.center()) {
+                            Some(color) => format!("{x}, {y} #{:06x}", color & 0xff_ff_ff),
+                            None => format!("{x}, {y}"),
+                        })
+                    }
+                    (SelectionMode::Area, _, _) if !selection.typed.is_empty() => {
+                        Some(format!("{}_", selection.typed))
+                    }
+                    (_, Some(Region { size, .. }), _) => {
+                        Some(format!("{}x{}", size.width, size.height))
+                    }
+                    _ => None,
+                };
+                let cursor_surface = cursor.and_then(|cursor| self.surface_at(surfaces, cursor));
+                for (index, surface) in surfaces.iter_mut().enumerate() {
+                    let pointer = cursor.filter(|_| cursor_surface == Some(index));
+                    surface.draw(pointer, region, label.as_deref(), loupe.as_ref());
+                }
+                redraw = false;
+            }
+
+            let wake = layer_state.next_repeat();
+            dispatch(event_queue, layer_state, freeze_queue, limit, wake)?;
+            layer_state.repeat_keys();
+            for input in std::mem::take(&mut layer_state.inputs) {
+                match input {
+                    OverlayInput::PointerMotion { surface, x, y } => {
+                        selection.cursor =
+                            global_position(surfaces, &surface, x, y).or(selection.cursor);
+                        redraw = true;
+                    }
+                    OverlayInput::PointerButton {
+                        button, pressed, ..
+                    } if button != BTN_LEFT => {
+                        if pressed {
+                            return Err(HaruhiError::Cancelled);
+                        }
+                    }
+                    OverlayInput::PointerButton {
+                        surface,
+                        x,
+                        y,
+                        pressed,
+                        ..
+                    } => {
+                        let Some(position) = global_position(surfaces, &surface, x, y) else {
+                            continue;
+                        };
+                        selection.cursor = Some(position);
+                        selection.typed.clear();
+                        redraw = true;
+                        match (self.mode, pressed) {
+                            (SelectionMode::Area, true) => {
+                                selection.anchor = Some(position);
+                                selection.dragging = true;
+                            }
+                            (SelectionMode::Area, false) if selection.dragging => {
+                                if let Some(region) = self.confirm(surfaces, &selection) {
+                                    return Ok(region);
+                                }
+                                selection.dragging = false;
+                            }
+                            (SelectionMode::Point | SelectionMode::Output, true) => {
+                                if let Some(region) = self.confirm(surfaces, &selection) {
+                                    return Ok(region);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    OverlayInput::Key {
+                        code,
+                        pressed: true,
+                        text,
+                    } => {
+                        if let Some(result) =
+                            self.handle_key(surfaces, &mut selection, layer_state, code, text)
+                        {
+                            return result;
+                        }
+                        redraw = true;
+                    }
+                    OverlayInput::Key { .. } => {}
+                }
+            }
+        }
+    }
+}
+
+/// The position on the surface in the global logical coordinates
+fn global_position(
+    surfaces: &[SelectorSurface],
+    surface: &WlSurface,
+    x: f64,
+    y: f64,
+) -> Option<Position> {
+    surfaces
+        .iter()
+        .find(|selector_surface| &selector_surface.surface == surface)
+        .map(|selector_surface| {
+            selector_surface.region.position
+                + Position {
+                    x: x.floor() as i32,
+                    y: y.floor() as i32,
+                }
+        })
+}
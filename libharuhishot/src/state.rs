@@ -32,9 +32,21 @@ use wayland_protocols::xdg::xdg_output::zv1::client::{
     zxdg_output_manager_v1::ZxdgOutputManagerV1,
     zxdg_output_v1::{self, ZxdgOutputV1},
 };
+use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+    zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+};
+use wayland_protocols_wlr::output_management::v1::client::{
+    zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+    zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+    zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+};
 
 use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
 
+use crate::bufferpool::BufferPool;
+use crate::dispatch::DispatchLimit;
 use crate::haruhierror::HaruhiError;
 use crate::utils::*;
 
@@ -42,15 +54,34 @@ use crate::utils::*;
 #[derive(Debug, Default)]
 pub struct HaruhiShotState {
     toplevels: Vec<TopLevel>,
+    wlr_toplevels: Vec<WlrTopLevel>,
+    output_heads: Vec<OutputHead>,
     output_infos: Vec<WlOutputInfo>,
     img_copy_manager: OnceLock<ExtImageCopyCaptureManagerV1>,
     output_image_manager: OnceLock<ExtOutputImageCaptureSourceManagerV1>,
     toplevel_image_manager: OnceLock<ExtForeignToplevelImageCaptureSourceManagerV1>,
+    foreign_toplevel_list: OnceLock<ExtForeignToplevelListV1>,
+    wlr_toplevel_manager: OnceLock<ZwlrForeignToplevelManagerV1>,
+    xdg_output_manager: OnceLock<ZxdgOutputManagerV1>,
     shm: OnceLock<WlShm>,
     qh: OnceLock<QueueHandle<Self>>,
     event_queue: Option<EventQueue<Self>>,
     conn: OnceLock<Connection>,
     globals: OnceLock<GlobalList>,
+    buffer_pool: BufferPool,
+    capture_timeout: Option<Duration>,
+    cancel_token: Option<CancelToken>,
+    output_callback: Option<ChangeCallback<OutputEvent>>,
+    toplevel_callback: Option<ChangeCallback<ToplevelEvent>>,
+}
+
+/// Called when an output or a toplevel is added, changed or removed
+struct ChangeCallback<E>(Box<dyn FnMut(E) + Send>);
+
+impl<E> std::fmt::Debug for ChangeCallback<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ChangeCallback")
+    }
 }
 
 impl HaruhiShotState {
@@ -62,16 +93,20 @@ impl HaruhiShotState {
         self.output_image_manager.get().expect("Should init")
     }
 
-    pub(crate) fn toplevel_image_manager(&self) -> &ExtForeignToplevelImageCaptureSourceManagerV1 {
-        self.toplevel_image_manager.get().expect("Should init")
+    pub(crate) fn toplevel_image_manager(
+        &self,
+    ) -> Result<&ExtForeignToplevelImageCaptureSourceManagerV1, HaruhiError> {
+        self.toplevel_image_manager
+            .get()
+            .ok_or(HaruhiError::ToplevelCaptureNotSupported)
     }
 
     pub(crate) fn qhandle(&self) -> &QueueHandle<Self> {
         self.qh.get().expect("Should init")
     }
 
-    pub(crate) fn take_event_queue(&mut self) -> EventQueue<Self> {
-        self.event_queue.take().expect("control your self")
+    pub(crate) fn take_event_queue(&mut self) -> Result<EventQueue<Self>, HaruhiError> {
+        self.event_queue.take().ok_or(HaruhiError::EventQueueInUse)
     }
 
     pub(crate) fn reset_event_queue(&mut self, event_queue: EventQueue<Self>) {
@@ -82,6 +117,133 @@ impl HaruhiShotState {
         self.shm.get().expect("Should init")
     }
 
+    pub(crate) fn buffer_pool(&self) -> &BufferPool {
+        &self.buffer_pool
+    }
+
+    pub(crate) fn buffer_pool_mut(&mut self) -> &mut BufferPool {
+        &mut self.buffer_pool
+    }
+
+    pub(crate) fn dispatch_limit(&self) -> DispatchLimit {
+        DispatchLimit::new(self.capture_timeout, self.cancel_token.clone())
+    }
+
+    /// The limit while waiting for the user, the capture timeout does not apply
+    pub(crate) fn interaction_limit(&self) -> DispatchLimit {
+        DispatchLimit::new(None, self.cancel_token.clone())
+    }
+
+    /// Give up a capture with [HaruhiError::Timeout] when the compositor does not finish it in
+    /// time. None means wait forever, which is the default
+    pub fn set_capture_timeout(&mut self, timeout: Option<Duration>) {
+        self.capture_timeout = timeout;
+    }
+
+    /// Stop waiting with [HaruhiError::Cancelled] once the token is cancelled
+    pub fn set_cancel_token(&mut self, token: Option<CancelToken>) {
+        self.cancel_token = token;
+    }
+
+    /// Be notified when an output is plugged, unplugged, or its mode, scale or position changed.
+    /// The events are only received while the event queue is dispatched, which happens during
+    /// the captures and in [HaruhiShotState::refresh]
+    pub fn set_output_change_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(OutputEvent) + Send + 'static,
+    {
+        self.output_callback = Some(ChangeCallback(Box::new(callback)));
+    }
+
+    /// Be notified when a window is opened, closed, or its title or app_id changed. Like
+    /// [HaruhiShotState::set_output_change_callback], it is called while the event queue is
+    /// dispatched
+    pub fn set_toplevel_change_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(ToplevelEvent) + Send + 'static,
+    {
+        self.toplevel_callback = Some(ChangeCallback(Box::new(callback)));
+    }
+
+    /// Process all the events the compositor sent since the last dispatch, so
+    /// [HaruhiShotState::outputs] is up to date after docking or undocking
+    pub fn refresh(&mut self) -> Result<(), HaruhiError> {
+        let mut event_queue = self.take_event_queue()?;
+        let result = event_queue.roundtrip(self);
+        self.reset_event_queue(event_queue);
+        result?;
+        Ok(())
+    }
+
+    fn notify_output_change(&mut self, event: OutputEvent) {
+        if let Some(ChangeCallback(callback)) = self.output_callback.as_mut() {
+            callback(event);
+        }
+    }
+
+    fn notify_toplevel_change(&mut self, event: ToplevelEvent) {
+        if let Some(ChangeCallback(callback)) = self.toplevel_callback.as_mut() {
+            callback(event);
+        }
+    }
+
+    /// The toplevel is still in the list, so it is not closed
+    pub(crate) fn toplevel_alive(&self, handle: &ExtForeignToplevelHandleV1) -> bool {
+        self.toplevels
+            .iter()
+            .any(|toplevel| toplevel.handle == *handle)
+    }
+
+    /// Give every toplevel the state of the wlr toplevel with the same app_id and title, each
+    /// wlr toplevel is used once. Return the indexes of the toplevels whose state changed
+    fn sync_wlr_toplevels(&mut self) -> Vec<usize> {
+        let mut used = vec![false; self.wlr_toplevels.len()];
+        let mut changed = Vec::new();
+        for (index, toplevel) in self.toplevels.iter_mut().enumerate() {
+            let matched = self
+                .wlr_toplevels
+                .iter()
+                .enumerate()
+                .position(|(wlr_index, wlr)| {
+                    !used[wlr_index]
+                        && wlr.current.app_id == toplevel.app_id
+                        && wlr.current.title == toplevel.title
+                });
+            let wlr_state = matched.map(|wlr_index| {
+                used[wlr_index] = true;
+                self.wlr_toplevels[wlr_index].current.state.clone()
+            });
+            if toplevel.wlr_state != wlr_state {
+                toplevel.wlr_state = wlr_state;
+                changed.push(index);
+            }
+        }
+        changed
+    }
+
+    /// The toplevel with the keyboard focus. Needs wlr-foreign-toplevel-management, see
+    /// [TopLevel::state_known]
+    pub fn focused_toplevel(&self) -> Option<&TopLevel> {
+        self.toplevels.iter().find(|toplevel| toplevel.activated())
+    }
+
+    /// Match the toplevels again after a wlr toplevel changed, and report the toplevels whose
+    /// state changed
+    fn notify_wlr_toplevel_change(&mut self) {
+        for index in self.sync_wlr_toplevels() {
+            let toplevel = &self.toplevels[index];
+            if toplevel.done_once {
+                self.notify_toplevel_change(ToplevelEvent::Changed(toplevel.clone()));
+            }
+        }
+    }
+
+    /// Destroy the shm buffers kept for the next captures, they will be created again when
+    /// needed
+    pub fn clear_buffer_pool(&mut self) {
+        self.buffer_pool.clear();
+    }
+
     /// get all outputs and their info
     pub fn outputs(&self) -> &Vec<WlOutputInfo> {
         &self.output_infos
@@ -114,12 +276,27 @@ impl HaruhiShotState {
             position: Position { x, y },
             name,
             description,
+            make,
+            model,
+            serial,
+            physical_size:
+                Size {
+                    width: physical_width,
+                    height: physical_height,
+                },
+            refresh,
             scale,
             ..
         } in self.outputs()
         {
             println!("{name}, {description}");
+            println!("    Make: {make}, Model: {model}");
+            if let Some(serial) = serial {
+                println!("    Serial: {serial}");
+            }
             println!("    Size: {width},{height}");
+            println!("    Refresh: {:.3} Hz", *refresh as f64 / 1000.);
+            println!("    PhysicalSize: {physical_width}mm, {physical_height}mm");
             println!("    LogicSize: {logical_width}, {logical_height}");
             println!("    Position: {x}, {y}");
             println!("    Scale: {scale}");
@@ -157,15 +334,53 @@ impl HaruhiShotState {
             .bind::<ExtForeignToplevelImageCaptureSourceManagerV1, _, _>(&qh, 1..=1, ())
             .ok();
         let shm = globals.bind::<WlShm, _, _>(&qh, 1..=2, ())?;
-        globals.bind::<ExtForeignToplevelListV1, _, _>(&qh, 1..=1, ())?;
-        let the_xdg_output_manager = globals.bind::<ZxdgOutputManagerV1, _, _>(&qh, 3..=3, ())?;
+        // Without the toplevel list, there is just no toplevel to capture
+        let foreign_toplevel_list = globals
+            .bind::<ExtForeignToplevelListV1, _, _>(&qh, 1..=1, ())
+            .ok();
+        // Only for the activated, maximized and fullscreen state of the toplevels
+        let wlr_toplevel_manager = globals
+            .bind::<ZwlrForeignToplevelManagerV1, _, _>(&qh, 1..=3, ())
+            .ok();
+        // Only for the serial of the monitors, the heads are matched to the outputs by name
+        let _output_manager = globals
+            .bind::<ZwlrOutputManagerV1, _, _>(&qh, 2..=4, ())
+            .ok();
+        // Without xdg output, the layout is calculated from wl_output
+        let the_xdg_output_manager = globals
+            .bind::<ZxdgOutputManagerV1, _, _>(&qh, 3..=3, ())
+            .ok();
+
+        if let Some(the_xdg_output_manager) = the_xdg_output_manager {
+            for output in state.output_infos.iter_mut() {
+                let xdg_the_output = the_xdg_output_manager.get_xdg_output(&output.output, &qh, ());
+                output.xdg_output.set(xdg_the_output).unwrap();
+            }
+            // set it now, so the outputs plugged from now on get their xdg output too
+            state
+                .xdg_output_manager
+                .set(the_xdg_output_manager)
+                .unwrap();
+        }
 
+        event_queue.roundtrip(&mut state)?;
         for output in state.output_infos.iter_mut() {
-            let xdg_the_output = the_xdg_output_manager.get_xdg_output(&output.output, &qh, ());
-            output.xdg_output.set(xdg_the_output).unwrap();
+            output.update_layout_from_wl_output();
         }
 
-        event_queue.blocking_dispatch(&mut state)?;
+        if let Some(foreign_toplevel_list) = foreign_toplevel_list {
+            state
+                .foreign_toplevel_list
+                .set(foreign_toplevel_list)
+                .unwrap();
+        }
+
+        if let Some(wlr_toplevel_manager) = wlr_toplevel_manager {
+            state
+                .wlr_toplevel_manager
+                .set(wlr_toplevel_manager)
+                .unwrap();
+        }
 
         if let Some(toplevel_image_manager) = toplevel_image_manager {
             state
@@ -198,17 +413,42 @@ delegate_noop!(HaruhiShotState: ignore WlShmPool);
 
 #[derive(Debug, Default)]
 pub(crate) struct FrameInfo {
-    buffer_size: OnceLock<Size<u32>>,
-    shm_format: OnceLock<WEnum<Format>>,
+    buffer_size: Option<Size<u32>>,
+    shm_formats: Vec<WEnum<Format>>,
+    done: bool,
+    stopped: bool,
 }
 
 impl FrameInfo {
-    pub(crate) fn size(&self) -> Size<u32> {
-        self.buffer_size.get().cloned().expect("not inited")
+    /// The buffer size, and the first format haruhishot can convert. The compositor sends one
+    /// event for every format it supports
+    pub(crate) fn constraints(&self) -> Result<(Size<u32>, Format), HaruhiError> {
+        let Some(size) = self.buffer_size else {
+            return Err(HaruhiError::MissingBufferSize);
+        };
+        let Some(format) = self.shm_formats.iter().find_map(|format| match format {
+            WEnum::Value(
+                format @ (Format::Xbgr2101010
+                | Format::Abgr2101010
+                | Format::Argb8888
+                | Format::Xrgb8888
+                | Format::Xbgr8888),
+            ) => Some(*format),
+            _ => None,
+        }) else {
+            return Err(HaruhiError::NotSupportFormat);
+        };
+        Ok((size, format))
     }
 
-    pub(crate) fn format(&self) -> WEnum<Format> {
-        self.shm_format.get().cloned().expect("Not inited")
+    /// All the buffer constraints have been sent
+    pub(crate) fn done(&self) -> bool {
+        self.done
+    }
+
+    /// The session will not produce frames anymore
+    pub(crate) fn stopped(&self) -> bool {
+        self.stopped
     }
 }
 
@@ -221,21 +461,20 @@ impl Dispatch<ExtImageCopyCaptureSessionV1, Arc<RwLock<FrameInfo>>> for HaruhiSh
         _conn: &Connection,
         _qhandle: &wayland_client::QueueHandle<Self>,
     ) {
-        let frame_info = data.write().unwrap();
+        let mut frame_info = data.write().unwrap();
         match event {
             ext_image_copy_capture_session_v1::Event::BufferSize { width, height } => {
-                frame_info
-                    .buffer_size
-                    .set(Size { width, height })
-                    .expect("should set only once");
+                frame_info.buffer_size = Some(Size { width, height });
             }
             ext_image_copy_capture_session_v1::Event::ShmFormat { format } => {
-                frame_info
-                    .shm_format
-                    .set(format)
-                    .expect("should set only once");
+                frame_info.shm_formats.push(format);
+            }
+            ext_image_copy_capture_session_v1::Event::Done => {
+                frame_info.done = true;
+            }
+            ext_image_copy_capture_session_v1::Event::Stopped => {
+                frame_info.stopped = true;
             }
-            ext_image_copy_capture_session_v1::Event::Done => {}
             _ => {}
         }
     }
@@ -261,11 +500,23 @@ impl CaptureInfo {
         }))
     }
 
-    pub(crate) fn transform(&self) -> wl_output::Transform {
-        self.transform
-    }
-    pub(crate) fn state(&self) -> CaptureState {
-        self.state
+    /// The transform of the frame once it is ready, None while it is pending
+    pub(crate) fn result(&self) -> Option<Result<wl_output::Transform, HaruhiError>> {
+        match self.state {
+            CaptureState::Succeeded => Some(Ok(self.transform)),
+            CaptureState::Failed(reason) => {
+                let reason = match reason {
+                    WEnum::Value(FailureReason::Stopped) => "Stopped".to_owned(),
+                    WEnum::Value(FailureReason::BufferConstraints) => {
+                        "BufferConstraints".to_owned()
+                    }
+                    WEnum::Value(_) => "Unknown".to_owned(),
+                    WEnum::Unknown(code) => format!("Unknown reason, code : {code}"),
+                };
+                Some(Err(HaruhiError::CaptureFailed(reason)))
+            }
+            CaptureState::Pending => None,
+        }
     }
 }
 
@@ -305,16 +556,41 @@ impl Dispatch<wl_registry::WlRegistry, ()> for HaruhiShotState {
         _conn: &wayland_client::Connection,
         qh: &wayland_client::QueueHandle<Self>,
     ) {
-        if let wl_registry::Event::Global {
-            name,
-            interface,
-            version,
-        } = event
-            && interface == WlOutput::interface().name {
-                state
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } if interface == WlOutput::interface().name => {
+                let version = version.min(WlOutput::interface().version);
+                let output_info = WlOutputInfo::new(proxy.bind(name, version, qh, ()), name);
+                // The outputs there during init get their xdg output there, this is for the
+                // outputs plugged later
+                if let Some(xdg_output_manager) = state.xdg_output_manager.get() {
+                    let xdg_output = xdg_output_manager.get_xdg_output(&output_info.output, qh, ());
+                    output_info.xdg_output.set(xdg_output).unwrap();
+                }
+                state.output_infos.push(output_info);
+            }
+            wl_registry::Event::GlobalRemove { name } => {
+                let Some(index) = state
                     .output_infos
-                    .push(WlOutputInfo::new(proxy.bind(name, version, qh, ())));
+                    .iter()
+                    .position(|output_info| output_info.global_name == name)
+                else {
+                    return;
+                };
+                let output_info = state.output_infos.remove(index);
+                if let Some(xdg_output) = output_info.xdg_output.get() {
+                    xdg_output.destroy();
+                }
+                if output_info.output.version() >= 3 {
+                    output_info.output.release();
+                }
+                state.notify_output_change(OutputEvent::Removed(output_info));
             }
+            _ => {}
+        }
     }
 }
 
@@ -327,13 +603,10 @@ impl Dispatch<ZxdgOutputV1, ()> for HaruhiShotState {
         _conn: &Connection,
         _qhandle: &wayland_client::QueueHandle<Self>,
     ) {
-        let Some(data) =
-            state
-                .output_infos
-                .iter_mut()
-                .find(|WlOutputInfo { xdg_output, .. }| {
-                    xdg_output.get().expect("we need to init here") == proxy
-                })
+        let Some(data) = state
+            .output_infos
+            .iter_mut()
+            .find(|WlOutputInfo { xdg_output, .. }| xdg_output.get() == Some(proxy))
         else {
             return;
         };
@@ -346,6 +619,10 @@ impl Dispatch<ZxdgOutputV1, ()> for HaruhiShotState {
             zxdg_output_v1::Event::Description { description } => {
                 data.description = description;
             }
+            // only used when wl_output is older than version 4, and has no name
+            zxdg_output_v1::Event::Name { name } if data.name.is_empty() => {
+                data.name = name;
+            }
             _ => {}
         }
     }
@@ -374,14 +651,50 @@ impl Dispatch<WlOutput, ()> for HaruhiShotState {
             wl_output::Event::Scale { factor } => {
                 data.scale = factor;
             }
-            wl_output::Event::Mode { width, height, .. } => {
+            wl_output::Event::Description { description } => {
+                data.description = description;
+            }
+            wl_output::Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                refresh,
+            } if flags.contains(wl_output::Mode::Current) => {
                 data.size = Size { width, height };
+                data.refresh = refresh;
+                data.mode_flags = flags;
             }
             wl_output::Event::Geometry {
-                transform: WEnum::Value(transform),
+                x,
+                y,
+                physical_width,
+                physical_height,
+                make,
+                model,
+                transform,
                 ..
             } => {
-                data.transform = transform;
+                data.wl_position = Position { x, y };
+                data.physical_size = Size {
+                    width: physical_width,
+                    height: physical_height,
+                };
+                data.make = make;
+                data.model = model;
+                if let WEnum::Value(transform) = transform {
+                    data.transform = transform;
+                }
+            }
+            wl_output::Event::Done => {
+                data.serial = serial_of(&state.output_heads, &data.name);
+                data.update_layout_from_wl_output();
+                let event = if data.done_once {
+                    OutputEvent::Changed(data.clone())
+                } else {
+                    data.done_once = true;
+                    OutputEvent::Added(data.clone())
+                };
+                state.notify_output_change(event);
             }
             _ => {}
         }
@@ -413,52 +726,217 @@ impl Dispatch<ExtForeignToplevelHandleV1, ()> for HaruhiShotState {
         _conn: &Connection,
         _qhandle: &wayland_client::QueueHandle<Self>,
     ) {
+        let Some(index) = state
+            .toplevels
+            .iter()
+            .position(|my_toplevel| my_toplevel.handle == *toplevel)
+        else {
+            return;
+        };
+        let current_info = &mut state.toplevels[index];
+        // The properties are applied together on done
         match event {
             ext_foreign_toplevel_handle_v1::Event::Title { title } => {
-                let Some(current_info) = state
-                    .toplevels
-                    .iter_mut()
-                    .find(|my_toplevel| my_toplevel.handle == *toplevel)
-                else {
-                    return;
-                };
-                current_info.title = title;
+                current_info.pending.title = Some(title);
             }
             ext_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
-                let Some(current_info) = state
-                    .toplevels
-                    .iter_mut()
-                    .find(|my_toplevel| my_toplevel.handle == *toplevel)
-                else {
-                    return;
-                };
-                current_info.app_id = app_id;
+                current_info.pending.app_id = Some(app_id);
             }
             ext_foreign_toplevel_handle_v1::Event::Identifier { identifier } => {
-                let Some(current_info) = state
-                    .toplevels
-                    .iter_mut()
-                    .find(|my_toplevel| my_toplevel.handle == *toplevel)
-                else {
-                    return;
+                current_info.pending.identifier = Some(identifier);
+            }
+            ext_foreign_toplevel_handle_v1::Event::Done => {
+                current_info.apply_pending();
+                // the title may match another wlr toplevel now, the event below has the state
+                state.sync_wlr_toplevels();
+                let current_info = &mut state.toplevels[index];
+                let event = if current_info.done_once {
+                    ToplevelEvent::Changed(current_info.clone())
+                } else {
+                    current_info.done_once = true;
+                    ToplevelEvent::Opened(current_info.clone())
                 };
-                current_info.identifier = identifier;
+                state.notify_toplevel_change(event);
             }
             ext_foreign_toplevel_handle_v1::Event::Closed => {
-                let Some(current_info) = state
-                    .toplevels
-                    .iter_mut()
-                    .find(|my_toplevel| my_toplevel.handle == *toplevel)
-                else {
-                    return;
-                };
-                current_info.active = false;
+                let mut closed = state.toplevels.remove(index);
+                closed.active = false;
+                toplevel.destroy();
+                state.notify_toplevel_change(ToplevelEvent::Closed(closed));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for HaruhiShotState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrForeignToplevelManagerV1,
+        event: <ZwlrForeignToplevelManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } = event {
+            state.wlr_toplevels.push(WlrTopLevel::new(toplevel));
+        }
+    }
+    event_created_child!(HaruhiShotState, ZwlrForeignToplevelHandleV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ())
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for HaruhiShotState {
+    fn event(
+        state: &mut Self,
+        toplevel: &ZwlrForeignToplevelHandleV1,
+        event: <ZwlrForeignToplevelHandleV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        let Some(index) = state
+            .wlr_toplevels
+            .iter()
+            .position(|wlr_toplevel| wlr_toplevel.handle == *toplevel)
+        else {
+            return;
+        };
+        let pending = &mut state.wlr_toplevels[index].pending;
+        match event {
+            zwlr_foreign_toplevel_handle_v1::Event::Title { title } => {
+                pending.title = title;
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                pending.app_id = app_id;
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::OutputEnter { output }
+                if !pending.state.outputs.contains(&output) =>
+            {
+                pending.state.outputs.push(output);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::OutputLeave { output } => {
+                pending.state.outputs.retain(|entered| *entered != output);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::State { state: flags } => {
+                let flags: Vec<u32> = flags
+                    .chunks_exact(4)
+                    .map(|flag| u32::from_ne_bytes([flag[0], flag[1], flag[2], flag[3]]))
+                    .collect();
+                let has =
+                    |flag: zwlr_foreign_toplevel_handle_v1::State| flags.contains(&(flag as u32));
+                pending.state.maximized = has(zwlr_foreign_toplevel_handle_v1::State::Maximized);
+                pending.state.minimized = has(zwlr_foreign_toplevel_handle_v1::State::Minimized);
+                pending.state.activated = has(zwlr_foreign_toplevel_handle_v1::State::Activated);
+                pending.state.fullscreen = has(zwlr_foreign_toplevel_handle_v1::State::Fullscreen);
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Done => {
+                let wlr_toplevel = &mut state.wlr_toplevels[index];
+                wlr_toplevel.current = wlr_toplevel.pending.clone();
+                state.notify_wlr_toplevel_change();
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                state.wlr_toplevels.remove(index);
+                toplevel.destroy();
+                state.notify_wlr_toplevel_change();
             }
             _ => {}
         }
     }
 }
 
+/// The serial of the head with the name, empty serials are treated as missing
+fn serial_of(heads: &[OutputHead], name: &str) -> Option<String> {
+    heads
+        .iter()
+        .find(|head| head.name == name)
+        .and_then(|head| head.serial.clone())
+        .filter(|serial| !serial.is_empty())
+}
+
+impl Dispatch<ZwlrOutputManagerV1, ()> for HaruhiShotState {
+    fn event(
+        state: &mut Self,
+        _proxy: &ZwlrOutputManagerV1,
+        event: <ZwlrOutputManagerV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_output_manager_v1::Event::Head { head } => {
+                state.output_heads.push(OutputHead::new(head));
+            }
+            // the heads can be complete after the wl_output done, so match them again
+            zwlr_output_manager_v1::Event::Done { .. } => {
+                for output_info in state.output_infos.iter_mut() {
+                    output_info.serial = serial_of(&state.output_heads, &output_info.name);
+                }
+            }
+            _ => {}
+        }
+    }
+    event_created_child!(HaruhiShotState, ZwlrOutputHeadV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ())
+    ]);
+}
+
+impl Dispatch<ZwlrOutputHeadV1, ()> for HaruhiShotState {
+    fn event(
+        state: &mut Self,
+        proxy: &ZwlrOutputHeadV1,
+        event: <ZwlrOutputHeadV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        let Some(index) = state
+            .output_heads
+            .iter()
+            .position(|output_head| output_head.head == *proxy)
+        else {
+            return;
+        };
+        match event {
+            zwlr_output_head_v1::Event::Name { name } => {
+                state.output_heads[index].name = name;
+            }
+            zwlr_output_head_v1::Event::SerialNumber { serial_number } => {
+                state.output_heads[index].serial = Some(serial_number);
+            }
+            zwlr_output_head_v1::Event::Finished => {
+                state.output_heads.remove(index);
+                if proxy.version() >= 3 {
+                    proxy.release();
+                }
+            }
+            _ => {}
+        }
+    }
+    event_created_child!(HaruhiShotState, ZwlrOutputModeV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ())
+    ]);
+}
+
+/// The modes are not used, they are only released once they are gone
+impl Dispatch<ZwlrOutputModeV1, ()> for HaruhiShotState {
+    fn event(
+        _state: &mut Self,
+        proxy: &ZwlrOutputModeV1,
+        event: <ZwlrOutputModeV1 as Proxy>::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qhandle: &wayland_client::QueueHandle<Self>,
+    ) {
+        if let zwlr_output_mode_v1::Event::Finished = event
+            && proxy.version() >= 3
+        {
+            proxy.release();
+        }
+    }
+}
+
 impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for HaruhiShotState {
     fn event(
         _state: &mut Self,
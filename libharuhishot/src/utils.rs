@@ -1,6 +1,9 @@
 use std::{
     ops::{Add, Sub},
-    sync::OnceLock,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 
 use wayland_client::protocol::wl_output::{self, WlOutput};
@@ -8,9 +11,13 @@ use wayland_protocols::{
     ext::foreign_toplevel_list::v1::client::ext_foreign_toplevel_handle_v1::ExtForeignToplevelHandleV1,
     xdg::xdg_output::zv1::client::zxdg_output_v1::ZxdgOutputV1,
 };
+use wayland_protocols_wlr::{
+    foreign_toplevel::v1::client::zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1,
+    output_management::v1::client::zwlr_output_head_v1::ZwlrOutputHeadV1,
+};
 
 /// Describe the size
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Size<T = i32>
 where
     T: Default,
@@ -20,7 +27,7 @@ where
 }
 
 /// Describe the position
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Position<T = i32>
 where
     T: Default,
@@ -114,9 +121,24 @@ pub struct WlOutputInfo {
     pub(crate) position: Position,
     pub(crate) name: String,
     pub(crate) description: String,
+    pub(crate) make: String,
+    pub(crate) model: String,
+    /// from wlr output management, None when the compositor does not send it
+    pub(crate) serial: Option<String>,
+    /// in millimeters
+    pub(crate) physical_size: Size,
+    /// in mHz
+    pub(crate) refresh: i32,
+    pub(crate) mode_flags: wl_output::Mode,
     pub(crate) xdg_output: OnceLock<ZxdgOutputV1>,
     pub(crate) transform: wl_output::Transform,
     pub(crate) scale: i32,
+    /// the position from wl_output, only used when there is no xdg output
+    pub(crate) wl_position: Position,
+    /// the name of the wl_output global, used to find the output when it is removed
+    pub(crate) global_name: u32,
+    /// the first done event is received, after it the output is complete
+    pub(crate) done_once: bool,
 }
 
 impl WlOutputInfo {
@@ -129,6 +151,77 @@ impl WlOutputInfo {
     pub fn description(&self) -> &str {
         &self.description
     }
+
+    /// get the manufacturer of the monitor
+    pub fn make(&self) -> &str {
+        &self.make
+    }
+
+    /// get the model of the monitor
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// get the serial number of the monitor, it needs wlr output management
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    /// The stable name of the monitor, like "Dell Inc. DELL U2720Q ABCD123". Unlike the connector
+    /// name, it stays the same between docks.
+    ///
+    /// It is make, model and serial when the compositor sends the serial with wlr output
+    /// management. Otherwise it falls back to the description if it ends with " (name)", the way
+    /// wlroots writes make, model and serial into it, and to make and model without a serial.
+    pub fn identifier(&self) -> String {
+        if let Some(serial) = self.serial.as_deref().filter(|serial| !serial.is_empty()) {
+            return format!("{} {} {serial}", self.make, self.model);
+        }
+        let suffix = format!(" ({})", self.name);
+        match self.description.strip_suffix(&suffix) {
+            Some(identifier) if !identifier.is_empty() => identifier.to_owned(),
+            _ => format!("{} {}", self.make, self.model),
+        }
+    }
+
+    /// The output is the one described by the connector name, or the make, model and serial.
+    /// The name can be a glob pattern with `*` and `?`, like `DP-*`
+    pub fn matches(&self, name: &str) -> bool {
+        glob_match(name, &self.name)
+            || glob_match(name, &self.identifier())
+            || glob_match(name, &format!("{} {}", self.make, self.model))
+    }
+
+    /// get the physical size in millimeters
+    pub fn physical_size(&self) -> Size {
+        self.physical_size
+    }
+
+    /// get the size of the current mode
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// get the refresh rate of the current mode in mHz
+    pub fn refresh(&self) -> i32 {
+        self.refresh
+    }
+
+    /// get the flags of the current mode, like if it is the preferred one
+    pub fn mode_flags(&self) -> wl_output::Mode {
+        self.mode_flags
+    }
+
+    /// get the transform
+    pub fn transform(&self) -> wl_output::Transform {
+        self.transform
+    }
+
+    /// get the scale
+    pub fn scale(&self) -> i32 {
+        self.scale
+    }
+
     /// get the wl_output
     pub fn wl_output(&self) -> &WlOutput {
         &self.output
@@ -143,7 +236,7 @@ impl WlOutputInfo {
     pub fn logical_size(&self) -> Size {
         self.logical_size
     }
-    pub(crate) fn new(output: WlOutput) -> Self {
+    pub(crate) fn new(output: WlOutput, global_name: u32) -> Self {
         Self {
             output,
             position: Position::default(),
@@ -151,9 +244,149 @@ impl WlOutputInfo {
             logical_size: Size::default(),
             name: "".to_owned(),
             description: "".to_owned(),
+            make: "".to_owned(),
+            model: "".to_owned(),
+            serial: None,
+            physical_size: Size::default(),
+            refresh: 0,
+            mode_flags: wl_output::Mode::empty(),
             xdg_output: OnceLock::new(),
             transform: wl_output::Transform::Normal,
             scale: 1,
+            wl_position: Position::default(),
+            global_name,
+            done_once: false,
+        }
+    }
+
+    /// Without xdg output, guess the layout from the mode, the scale and the transform of
+    /// wl_output. It is exact unless the compositor uses fractional scale
+    pub(crate) fn update_layout_from_wl_output(&mut self) {
+        if self.xdg_output.get().is_some() {
+            return;
+        }
+        let scale = self.scale.max(1);
+        let Size { width, height } = self.size;
+        let (width, height) = match self.transform {
+            wl_output::Transform::_90
+            | wl_output::Transform::_270
+            | wl_output::Transform::Flipped90
+            | wl_output::Transform::Flipped270 => (height, width),
+            _ => (width, height),
+        };
+        self.position = self.wl_position;
+        self.logical_size = Size {
+            width: width / scale,
+            height: height / scale,
+        };
+    }
+}
+
+/// Match the text with a pattern, `*` matches any characters and `?` matches one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // the position of the last `*` in pattern, and the text position it is tried from
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Describe how the outputs changed, see [crate::HaruhiShotState::set_output_change_callback]
+#[derive(Debug, Clone)]
+pub enum OutputEvent {
+    /// A new output is plugged, with all its information
+    Added(WlOutputInfo),
+    /// The mode, scale, transform or position of the output changed
+    Changed(WlOutputInfo),
+    /// The output is unplugged
+    Removed(WlOutputInfo),
+}
+
+/// Describe how the toplevels changed, see [crate::HaruhiShotState::set_toplevel_change_callback]
+#[derive(Debug, Clone)]
+pub enum ToplevelEvent {
+    /// A new window is opened, with its title and app_id
+    Opened(TopLevel),
+    /// The title or the app_id changed
+    Changed(TopLevel),
+    /// The window is closed, it is not in the list anymore
+    Closed(TopLevel),
+}
+
+/// The properties received, but not applied before the done event
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PendingTopLevel {
+    pub(crate) title: Option<String>,
+    pub(crate) app_id: Option<String>,
+    pub(crate) identifier: Option<String>,
+}
+
+/// The state a wlr foreign toplevel handle reports, ext-foreign-toplevel-list does not have it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct WlrToplevelState {
+    pub(crate) activated: bool,
+    pub(crate) maximized: bool,
+    pub(crate) minimized: bool,
+    pub(crate) fullscreen: bool,
+    pub(crate) outputs: Vec<WlOutput>,
+}
+
+/// The properties of a wlr foreign toplevel handle, applied on done
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WlrTopLevelProperties {
+    pub(crate) title: String,
+    pub(crate) app_id: String,
+    pub(crate) state: WlrToplevelState,
+}
+
+/// A head from wlr-output-management, only used for the serial of the output with the same name
+#[derive(Debug, Clone)]
+pub(crate) struct OutputHead {
+    pub(crate) head: ZwlrOutputHeadV1,
+    pub(crate) name: String,
+    pub(crate) serial: Option<String>,
+}
+
+impl OutputHead {
+    pub(crate) fn new(head: ZwlrOutputHeadV1) -> Self {
+        Self {
+            head,
+            name: "".to_owned(),
+            serial: None,
+        }
+    }
+}
+
+/// A window from wlr-foreign-toplevel-management, matched to [TopLevel] by app_id and title
+#[derive(Debug, Clone)]
+pub(crate) struct WlrTopLevel {
+    pub(crate) handle: ZwlrForeignToplevelHandleV1,
+    pub(crate) current: WlrTopLevelProperties,
+    pub(crate) pending: WlrTopLevelProperties,
+}
+
+impl WlrTopLevel {
+    pub(crate) fn new(handle: ZwlrForeignToplevelHandleV1) -> Self {
+        Self {
+            handle,
+            current: WlrTopLevelProperties::default(),
+            pending: WlrTopLevelProperties::default(),
         }
     }
 }
@@ -165,6 +398,11 @@ pub struct TopLevel {
     pub(crate) app_id: String,
     pub(crate) identifier: String,
     pub(crate) active: bool,
+    pub(crate) pending: PendingTopLevel,
+    /// the first done event is received, after it the toplevel is complete
+    pub(crate) done_once: bool,
+    /// None without wlr-foreign-toplevel-management, or when no wlr toplevel matches
+    pub(crate) wlr_state: Option<WlrToplevelState>,
 }
 
 impl TopLevel {
@@ -175,6 +413,26 @@ impl TopLevel {
             app_id: "".to_owned(),
             identifier: "".to_owned(),
             active: true,
+            pending: PendingTopLevel::default(),
+            done_once: false,
+            wlr_state: None,
+        }
+    }
+
+    pub(crate) fn apply_pending(&mut self) {
+        let PendingTopLevel {
+            title,
+            app_id,
+            identifier,
+        } = std::mem::take(&mut self.pending);
+        if let Some(title) = title {
+            self.title = title;
+        }
+        if let Some(app_id) = app_id {
+            self.app_id = app_id;
+        }
+        if let Some(identifier) = identifier {
+            self.identifier = identifier;
         }
     }
 
@@ -197,7 +455,99 @@ impl TopLevel {
         &self.handle
     }
 
+    /// false once the toplevel is closed, closed toplevels are removed from
+    /// [crate::HaruhiShotState::toplevels]
     pub fn active(&self) -> bool {
         self.active
     }
+
+    /// The activated, maximized, fullscreen state and the outputs are known. They come from
+    /// wlr-foreign-toplevel-management, so it is false when the compositor does not have it
+    pub fn state_known(&self) -> bool {
+        self.wlr_state.is_some()
+    }
+
+    /// The window has the keyboard focus
+    pub fn activated(&self) -> bool {
+        self.wlr_state.as_ref().is_some_and(|state| state.activated)
+    }
+
+    pub fn maximized(&self) -> bool {
+        self.wlr_state.as_ref().is_some_and(|state| state.maximized)
+    }
+
+    pub fn minimized(&self) -> bool {
+        self.wlr_state.as_ref().is_some_and(|state| state.minimized)
+    }
+
+    pub fn fullscreen(&self) -> bool {
+        self.wlr_state
+            .as_ref()
+            .is_some_and(|state| state.fullscreen)
+    }
+
+    /// The outputs the window is on
+    pub fn outputs(&self) -> &[WlOutput] {
+        self.wlr_state
+            .as_ref()
+            .map_or(&[], |state| state.outputs.as_slice())
+    }
+}
+
+/// Stop a running capture, it can be cancelled from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// cancel all the captures using this token
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn glob_without_wildcards_is_exact() {
+        assert!(glob_match("DP-1", "DP-1"));
+        assert!(!glob_match("DP-1", "DP-10"));
+        assert!(!glob_match("DP-10", "DP-1"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "DP-1"));
+    }
+
+    #[test]
+    fn glob_star() {
+        assert!(glob_match("DP-*", "DP-1"));
+        assert!(glob_match("DP-*", "DP-"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "HDMI-A-1"));
+        assert!(glob_match("**", "eDP-1"));
+        assert!(glob_match("*-1", "HDMI-A-1"));
+        assert!(glob_match("*DELL*", "Dell Inc. DELL U2720Q ABCD123"));
+        assert!(glob_match("D*-*-1", "DP-A-B-1"));
+        assert!(!glob_match("DP-*", "eDP-1"));
+        assert!(!glob_match("*-2", "HDMI-A-1"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(glob_match("DP-?", "DP-1"));
+        assert!(!glob_match("DP-?", "DP-"));
+        assert!(!glob_match("DP-?", "DP-10"));
+        assert!(glob_match("DP-??", "DP-10"));
+        assert!(glob_match("?*", "a"));
+        assert!(!glob_match("?*", ""));
+        assert!(glob_match("*?", "DP-1"));
+    }
 }
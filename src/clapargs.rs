@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Debug, Parser, PartialEq, Eq)]
 #[command(
@@ -7,6 +7,54 @@ use clap::Parser;
     long_about = None,
     version,
 )]
+pub struct HaruhiArgs {
+    #[command(subcommand)]
+    pub command: HaruhiCli,
+    /// give up when the compositor does not finish the capture in time
+    #[arg(value_name = "SECONDS", long, global = true)]
+    pub timeout: Option<u64>,
+    /// do not show notifications, errors are printed to stderr instead
+    #[arg(long, global = true)]
+    pub no_notify: bool,
+    /// the urgency of the notifications
+    #[arg(value_name = "URGENCY", long, global = true, default_value = "normal")]
+    pub urgency: NotifyUrgency,
+    /// how long the notifications stay, 0 keeps them until they are closed
+    #[arg(value_name = "SECONDS", long, global = true)]
+    pub notify_timeout: Option<u32>,
+}
+
+/// Where the image goes, they can be combined. The image is saved to a file when none is given
+#[derive(Debug, Args, Clone, PartialEq, Eq)]
+pub struct ImageSinks {
+    #[arg(value_name = "stdout", long)]
+    pub stdout: bool,
+    /// copy the image to the clipboard, it is saved to a file too
+    #[arg(long)]
+    pub clipboard: bool,
+    /// save the image to a file, along with the other sinks
+    #[arg(long)]
+    pub save: bool,
+    /// write the image to the stdin of the shell command, can be given more than once
+    #[arg(value_name = "CMD", long)]
+    pub pipe: Vec<String>,
+}
+
+/// What runs after the image is saved, with `--split` it runs for each output
+#[derive(Debug, Args, Clone, PartialEq, Eq)]
+pub struct Hooks {
+    /// run the shell command after the image is saved, can be given more than once.
+    /// HARUHISHOT_EXEC is used without it. The file and what was captured are in the
+    /// HARUHISHOT_* environment variables
+    #[arg(value_name = "CMD", long)]
+    pub exec: Vec<String>,
+    /// open the image in HARUHISHOT_EDITOR, swappy by default, and wait for it before the
+    /// other sinks and hooks
+    #[arg(long)]
+    pub edit: bool,
+}
+
+#[derive(Debug, Subcommand, PartialEq, Eq)]
 pub enum HaruhiCli {
     #[command(
         long_flag = "list-outputs",
@@ -16,34 +64,151 @@ pub enum HaruhiCli {
     ListOutputs,
     #[command(long_flag = "output", short_flag = 'O', about = "choose output")]
     Output {
+        /// output names, make model serial or glob patterns like 'DP-*'
         #[arg(required = false)]
-        output: Option<String>,
-        #[arg(value_name = "stdout", long)]
-        stdout: bool,
+        output: Vec<String>,
+        /// one more output, so `-O DP-1 -O HDMI-A-1` works too
+        #[arg(value_name = "OUTPUT", short = 'O', long = "output", action = ArgAction::Append)]
+        more_output: Vec<String>,
+        /// leave out the outputs matching it
+        #[arg(value_name = "OUTPUT", long)]
+        exclude_output: Vec<String>,
+        #[command(flatten)]
+        sinks: ImageSinks,
+        #[command(flatten)]
+        hooks: Hooks,
+        #[arg(value_name = "pointer", long, default_value = "false")]
+        cursor: bool,
+    },
+    #[command(
+        long_flag = "output-under-pointer",
+        about = "capture the output the pointer is on"
+    )]
+    OutputUnderPointer {
+        #[command(flatten)]
+        sinks: ImageSinks,
+        #[command(flatten)]
+        hooks: Hooks,
         #[arg(value_name = "pointer", long, default_value = "false")]
         cursor: bool,
     },
     #[command(long_flag = "slurp", short_flag = 'S', about = "area select")]
     Slurp {
-        #[arg(value_name = "stdout", long)]
-        stdout: bool,
+        #[command(flatten)]
+        sinks: ImageSinks,
+        #[command(flatten)]
+        hooks: Hooks,
         #[arg(value_name = "pointer", long, default_value = "false")]
         cursor: bool,
     },
     #[command(long_flag = "application", about = "application shot")]
     Application {
-        #[arg(value_name = "stdout", long)]
-        stdout: bool,
+        #[command(flatten)]
+        sinks: ImageSinks,
+        #[command(flatten)]
+        hooks: Hooks,
         #[arg(value_name = "pointer", long, default_value = "false")]
         cursor: bool,
+        /// how to choose the application, gui when stdin is not a terminal by default
+        #[arg(value_name = "MODE", long, conflicts_with = "focused")]
+        pick: Option<PickMode>,
+        /// capture the focused window without choosing
+        #[arg(long)]
+        focused: bool,
     },
-    #[command(long_flag = "fullscreen", short_flag = 'F', about = "capture all outputs")]
+    #[command(
+        long_flag = "fullscreen",
+        short_flag = 'F',
+        about = "capture all outputs"
+    )]
     Fullscreen {
-        #[arg(value_name = "stdout", long)]
-        stdout: bool,
+        /// only capture the outputs matching it, glob patterns like 'DP-*' are supported
+        #[arg(value_name = "OUTPUT", short = 'O', long)]
+        output: Vec<String>,
+        /// leave out the outputs matching it
+        #[arg(value_name = "OUTPUT", long)]
+        exclude_output: Vec<String>,
+        #[command(flatten)]
+        sinks: ImageSinks,
+        #[command(flatten)]
+        hooks: Hooks,
         #[arg(value_name = "pointer", long, default_value = "false")]
         cursor: bool,
+        /// save one image for each output, and a json file with their layout
+        #[arg(long, conflicts_with_all = ["stdout", "clipboard", "save", "pipe"])]
+        split: bool,
     },
     #[command(long_flag = "color", short_flag = 'C', about = "get color")]
-    Color,
+    Color {
+        /// how to print the color, without it the rgba values and hex are printed
+        #[arg(value_name = "FORMAT", long)]
+        color_format: Option<ColorFormat>,
+        /// the average color of N×N pixels around the point
+        #[arg(
+            value_name = "N",
+            long,
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u32).range(1..=99)
+        )]
+        average: u32,
+        /// copy the color to the clipboard, as hex without --color-format
+        #[arg(long)]
+        copy: bool,
+        /// pick a text color and then its background, and check their WCAG contrast
+        #[arg(long)]
+        pairs: bool,
+        /// keep picking until Escape is pressed
+        #[arg(long = "loop")]
+        repeat: bool,
+    },
+    #[command(long_flag = "palette", about = "get the main colors of an area")]
+    Palette {
+        /// how many colors
+        #[arg(value_name = "N", value_parser = clap::value_parser!(u32).range(1..=256))]
+        count: u32,
+        /// how to print the colors, hex by default. json prints the size of the area too
+        #[arg(value_name = "FORMAT", long)]
+        color_format: Option<ColorFormat>,
+        /// print json with the min, max, mean and histogram of each channel
+        #[arg(long)]
+        stats: bool,
+    },
+    #[command(
+        long_flag = "doctor",
+        about = "check which features work on the current compositor"
+    )]
+    Doctor,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+pub enum PickMode {
+    /// a fuzzy list in the terminal
+    Tty,
+    /// thumbnails on the screen, chosen with the pointer or the keyboard
+    Gui,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+pub enum NotifyUrgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+pub enum ColorFormat {
+    /// #rrggbb
+    Hex,
+    /// rgb(r, g, b)
+    Rgb,
+    /// hsl(h, s%, l%)
+    Hsl,
+    /// hsv(h, s%, v%)
+    Hsv,
+    /// oklch(l% c h)
+    Oklch,
+    /// rgba(r, g, b, a)
+    CssRgba,
+    /// every format with the position
+    Json,
 }
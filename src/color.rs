@@ -0,0 +1,394 @@
+use image::RgbaImage;
+use libharuhishot::{Position, Region};
+use serde_json::json;
+
+use crate::clapargs::ColorFormat;
+
+/// The color picked by `--color`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PickedColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl PickedColor {
+    /// The average color of the square of `sample_size` logical pixels around the position,
+    /// over all the physical pixels of the image it covers. The position is in the global
+    /// logical coordinates, and `output` is the region of the output the image shows
+    pub fn sample(
+        image: &RgbaImage,
+        output: Region,
+        position: Position,
+        sample_size: u32,
+    ) -> Option<Self> {
+        let sample_size = sample_size.max(1) as i32;
+        let Position { x, y } = position
+            - output.position
+            - Position {
+                x: sample_size / 2,
+                y: sample_size / 2,
+            };
+        // the logical square inside the output, then the physical pixels it covers
+        let left = x.max(0) as u64;
+        let top = y.max(0) as u64;
+        let right = (x + sample_size).min(output.size.width).max(0) as u64;
+        let bottom = (y + sample_size).min(output.size.height).max(0) as u64;
+        if left >= right || top >= bottom || image.width() == 0 || image.height() == 0 {
+            return None;
+        }
+        let (width, height) = (output.size.width as u64, output.size.height as u64);
+        let (image_width, image_height) = (image.width() as u64, image.height() as u64);
+        let to_physical = |start: u64, end: u64, logical: u64, physical: u64| {
+            let start = start * physical / logical;
+            let end = (end * physical)
+                .div_ceil(logical)
+                .clamp(start + 1, physical);
+            start as u32..end as u32
+        };
+        let mut sum = [0u64; 4];
+        let mut count = 0;
+        for y in to_physical(top, bottom, height, image_height) {
+            for x in to_physical(left, right, width, image_width) {
+                let pixel = image.get_pixel(x, y);
+                for (sum, channel) in sum.iter_mut().zip(pixel.0) {
+                    *sum += channel as u64;
+                }
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return None;
+        }
+        let [r, g, b, a] = sum.map(|sum| ((sum + count / 2) / count) as u8);
+        Some(Self { r, g, b, a })
+    }
+
+    pub fn hex(self) -> String {
+        let Self { r, g, b, a } = self;
+        if a == u8::MAX {
+            format!("#{r:02x}{g:02x}{b:02x}")
+        } else {
+            format!("#{r:02x}{g:02x}{b:02x}{a:02x}")
+        }
+    }
+
+    fn channels(self) -> [f64; 3] {
+        [self.r, self.g, self.b].map(|channel| channel as f64 / 255.)
+    }
+
+    /// The channels without the sRGB gamma
+    fn linear_channels(self) -> [f64; 3] {
+        self.channels().map(|channel| {
+            if channel <= 0.04045 {
+                channel / 12.92
+            } else {
+                ((channel + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    }
+
+    /// The relative luminance of WCAG 2.x
+    fn luminance(self) -> f64 {
+        let [r, g, b] = self.linear_channels();
+        0.2126 * r + 0.7152 * g + 0.0722 * b
+    }
+
+    /// The hue in degrees, with the max and min channel
+    fn hue(self) -> (f64, f64, f64) {
+        let [r, g, b] = self.channels();
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+        let hue = if delta == 0. {
+            0.
+        } else if max == r {
+            60. * ((g - b) / delta).rem_euclid(6.)
+        } else if max == g {
+            60. * ((b - r) / delta + 2.)
+        } else {
+            60. * ((r - g) / delta + 4.)
+        };
+        (hue, max, min)
+    }
+
+    /// Hue in degrees, saturation and lightness between 0 and 1
+    fn hsl(self) -> [f64; 3] {
+        let (hue, max, min) = self.hue();
+        let lightness = (max + min) / 2.;
+        let saturation = if max == min {
+            0.
+        } else {
+            (max - min) / (1. - (2. * lightness - 1.).abs())
+        };
+        [hue, saturation, lightness]
+    }
+
+    /// Hue in degrees, saturation and value between 0 and 1
+    fn hsv(self) -> [f64; 3] {
+        let (hue, max, min) = self.hue();
+        let saturation = if max == 0. { 0. } else { (max - min) / max };
+        [hue, saturation, max]
+    }
+
+    /// Lightness between 0 and 1, chroma and hue in degrees
+    fn oklch(self) -> [f64; 3] {
+        let [r, g, b] = self.linear_channels();
+        let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+        let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+        let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+        let lightness = 0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s;
+        let a = 1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s;
+        let b = 0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s;
+        let chroma = a.hypot(b);
+        // the hue of grays is only rounding noise
+        let hue = if chroma < 1e-4 {
+            0.
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.)
+        };
+        [lightness, chroma, hue]
+    }
+
+    fn alpha(self) -> f64 {
+        (self.a as f64 / 255. * 100.).round() / 100.
+    }
+
+    /// The color as text, json is handled by [PickedColor::to_json]
+    pub fn format(self, format: ColorFormat) -> String {
+        let Self { r, g, b, .. } = self;
+        match format {
+            ColorFormat::Hex => self.hex(),
+            ColorFormat::Rgb => format!("rgb({r}, {g}, {b})"),
+            ColorFormat::Hsl => {
+                let [hue, saturation, lightness] = self.hsl();
+                format!(
+                    "hsl({hue:.0}, {:.0}%, {:.0}%)",
+                    saturation * 100.,
+                    lightness * 100.
+                )
+            }
+            ColorFormat::Hsv => {
+                let [hue, saturation, value] = self.hsv();
+                format!(
+                    "hsv({hue:.0}, {:.0}%, {:.0}%)",
+                    saturation * 100.,
+                    value * 100.
+                )
+            }
+            ColorFormat::Oklch => {
+                let [lightness, chroma, hue] = self.oklch();
+                format!("oklch({:.2}% {chroma:.4} {hue:.2})", lightness * 100.)
+            }
+            ColorFormat::CssRgba => format!("rgba({r}, {g}, {b}, {})", self.alpha()),
+            ColorFormat::Json => self.to_json(None, 1).to_string(),
+        }
+    }
+
+    /// Every format at once, with where the color was picked
+    pub fn to_json(self, position: Option<Position>, sample_size: u32) -> serde_json::Value {
+        let round = |values: [f64; 3]| values.map(|value| (value * 10000.).round() / 10000.);
+        let Self { r, g, b, a } = self;
+        json!({
+            "x": position.map(|position| position.x),
+            "y": position.map(|position| position.y),
+            "sample_size": sample_size,
+            "hex": self.hex(),
+            "rgba": [r, g, b, a],
+            "hsl": round(self.hsl()),
+            "hsv": round(self.hsv()),
+            "oklch": round(self.oklch()),
+            "css": self.format(ColorFormat::CssRgba),
+        })
+    }
+}
+
+/// The contrast ratios of WCAG 2.x each level needs, for normal and for large text
+const WCAG_LEVELS: [(&str, f64, f64); 2] = [("AA", 4.5, 3.), ("AAA", 7., 4.5)];
+
+/// A text color on a background color, picked by `--color --pairs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contrast {
+    pub foreground: PickedColor,
+    pub background: PickedColor,
+}
+
+impl Contrast {
+    /// The contrast ratio of WCAG 2.x, from 1 to 21
+    pub fn ratio(self) -> f64 {
+        let foreground = self.foreground.luminance();
+        let background = self.background.luminance();
+        (foreground.max(background) + 0.05) / (foreground.min(background) + 0.05)
+    }
+
+    /// The level, and whether normal and large text pass it
+    fn levels(self) -> impl Iterator<Item = (&'static str, bool, bool)> {
+        let ratio = self.ratio();
+        WCAG_LEVELS
+            .into_iter()
+            .map(move |(level, normal, large)| (level, ratio >= normal, ratio >= large))
+    }
+
+    /// The result of every level, one on each line
+    pub fn summary(self) -> String {
+        let result = |pass: bool| if pass { "pass" } else { "fail" };
+        self.levels()
+            .map(|(level, normal, large)| {
+                format!(
+                    "{level} normal text: {}\n{level} large text: {}",
+                    result(normal),
+                    result(large)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The colors, the ratio and every level. The colors are hex without a format
+    pub fn report(self, color_format: Option<ColorFormat>) -> String {
+        let color = |color: PickedColor| match color_format {
+            None | Some(ColorFormat::Json) => color.hex(),
+            Some(color_format) => color.format(color_format),
+        };
+        format!(
+            "foreground: {}\nbackground: {}\ncontrast: {:.2}:1\n{}",
+            color(self.foreground),
+            color(self.background),
+            self.ratio(),
+            self.summary()
+        )
+    }
+
+    /// Everything of the report, with where the colors were picked
+    pub fn to_json(self, positions: [Position; 2], sample_size: u32) -> serde_json::Value {
+        let mut json = json!({
+            "foreground": self.foreground.to_json(Some(positions[0]), sample_size),
+            "background": self.background.to_json(Some(positions[1]), sample_size),
+            "contrast": (self.ratio() * 100.).round() / 100.,
+        });
+        for (level, normal, large) in self.levels() {
+            json[level.to_lowercase()] = json!({ "normal": normal, "large": large });
+        }
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Contrast, PickedColor};
+    use libharuhishot::{Position, Region, Size};
+
+    const BLACK: PickedColor = rgb(0, 0, 0);
+    const WHITE: PickedColor = rgb(255, 255, 255);
+
+    const fn rgb(r: u8, g: u8, b: u8) -> PickedColor {
+        PickedColor { r, g, b, a: 255 }
+    }
+
+    fn assert_close(actual: [f64; 3], expected: [f64; 3]) {
+        for (actual, expected) in actual.into_iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-3,
+                "{actual} is not {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn black_on_white_is_21() {
+        let contrast = Contrast {
+            foreground: BLACK,
+            background: WHITE,
+        };
+        assert!((contrast.ratio() - 21.).abs() < 1e-9);
+        assert!(contrast.levels().all(|(_, normal, large)| normal && large));
+    }
+
+    #[test]
+    fn ratio_ignores_the_order() {
+        let contrast = Contrast {
+            foreground: WHITE,
+            background: rgb(0x77, 0x77, 0x77),
+        };
+        let swapped = Contrast {
+            foreground: contrast.background,
+            background: contrast.foreground,
+        };
+        assert_eq!(contrast.ratio(), swapped.ratio());
+    }
+
+    #[test]
+    fn gray_777_on_white_fails_aa() {
+        let contrast = Contrast {
+            foreground: rgb(0x77, 0x77, 0x77),
+            background: WHITE,
+        };
+        assert!((contrast.ratio() - 4.48).abs() < 0.01);
+        let levels: Vec<_> = contrast.levels().collect();
+        assert_eq!(levels, [("AA", false, true), ("AAA", false, false)]);
+    }
+
+    #[test]
+    fn same_colors_are_1() {
+        let contrast = Contrast {
+            foreground: rgb(0x33, 0x66, 0x99),
+            background: rgb(0x33, 0x66, 0x99),
+        };
+        assert!((contrast.ratio() - 1.).abs() < 1e-9);
+        assert!(
+            contrast
+                .levels()
+                .all(|(_, normal, large)| !normal && !large)
+        );
+    }
+
+    #[test]
+    fn hsl() {
+        assert_close(rgb(255, 0, 0).hsl(), [0., 1., 0.5]);
+        assert_close(rgb(0, 255, 0).hsl(), [120., 1., 0.5]);
+        assert_close(rgb(0, 0, 255).hsl(), [240., 1., 0.5]);
+        assert_close(rgb(255, 0, 255).hsl(), [300., 1., 0.5]);
+        assert_close(BLACK.hsl(), [0., 0., 0.]);
+        assert_close(WHITE.hsl(), [0., 0., 1.]);
+        assert_close(rgb(0x80, 0x80, 0x80).hsl(), [0., 0., 128. / 255.]);
+    }
+
+    #[test]
+    fn hsv() {
+        assert_close(rgb(255, 0, 0).hsv(), [0., 1., 1.]);
+        assert_close(rgb(0, 128, 0).hsv(), [120., 1., 128. / 255.]);
+        assert_close(rgb(255, 255, 0).hsv(), [60., 1., 1.]);
+        assert_close(BLACK.hsv(), [0., 0., 0.]);
+        assert_close(WHITE.hsv(), [0., 0., 1.]);
+    }
+
+    #[test]
+    fn oklch() {
+        assert_close(rgb(255, 0, 0).oklch(), [0.62796, 0.25768, 29.2339]);
+        assert_close(rgb(0, 0, 255).oklch(), [0.45201, 0.31321, 264.052]);
+        assert_close(WHITE.oklch(), [1., 0., 0.]);
+        assert_close(BLACK.oklch(), [0., 0., 0.]);
+    }
+
+    #[test]
+    fn sample_averages_the_physical_pixels() {
+        // a scale 2 output, the logical pixel (1, 0) covers a red and a blue column
+        let mut image = image::RgbaImage::from_pixel(4, 2, image::Rgba([0, 0, 0, 255]));
+        for y in 0..2 {
+            image.put_pixel(2, y, image::Rgba([255, 0, 0, 255]));
+            image.put_pixel(3, y, image::Rgba([0, 0, 255, 255]));
+        }
+        let output = Region {
+            position: Position { x: 10, y: 0 },
+            size: Size {
+                width: 2,
+                height: 1,
+            },
+        };
+        let color = PickedColor::sample(&image, output, Position { x: 11, y: 0 }, 1);
+        assert_eq!(color, Some(rgb(128, 0, 128)));
+        let outside = PickedColor::sample(&image, output, Position { x: 12, y: 0 }, 1);
+        assert_eq!(outside, None);
+    }
+}
@@ -1,22 +1,32 @@
 mod clapargs;
+mod color;
+mod palette;
 
 use clap::Parser;
 use dialoguer::FuzzySelect;
 use dialoguer::theme::ColorfulTheme;
 use image::codecs::png::PngEncoder;
-use image::{GenericImageView, ImageEncoder, ImageError, Rgba};
+use image::{GenericImageView, ImageEncoder, ImageError};
 pub use libharuhishot::HaruhiShotState;
 use libharuhishot::reexport::Transform;
 use libharuhishot::{
-    CaptureOption, ClipImageViewInfoArea, ClipRegion, ImageInfo, Position, Region, Size,
+    AreaSelectCallback, Capabilities, CaptureOption, ClipImageViewInfo, ClipImageViewInfoArea,
+    ClipRegion, ClipboardContent, Feature, ImageInfo, Position, Protocol, Region, RegionSelector,
+    SelectionMode, Size, TopLevel, WlOutputInfo,
 };
 
-use std::io::{BufWriter, Write, stdout};
-use std::{env, fs, path::PathBuf};
+use std::io::{IsTerminal, Read, Write, stdin, stdout};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::{env, fs};
 
+use std::os::fd::AsRawFd;
 use std::sync::LazyLock;
+use std::sync::atomic::{AtomicI32, Ordering};
 
-use clapargs::HaruhiCli;
+use clapargs::{ColorFormat, HaruhiArgs, HaruhiCli, Hooks, ImageSinks, NotifyUrgency, PickMode};
+use color::{Contrast, PickedColor};
+use palette::PaletteEntry;
 
 const TMP: &str = "/tmp";
 
@@ -24,6 +34,24 @@ pub const SUCCEED_IMAGE: &str = "haruhi_succeeded";
 pub const FAILED_IMAGE: &str = "haruhi_failed";
 pub const TIMEOUT: i32 = 10000;
 
+/// The hook used without `--exec`
+const EXEC_ENV: &str = "HARUHISHOT_EXEC";
+/// The editor of `--edit`, the file is given as its last argument
+const EDITOR_ENV: &str = "HARUHISHOT_EDITOR";
+const DEFAULT_EDITOR: &str = "swappy -f";
+
+/// The fd of the connection to the compositor, the forked processes close their copy of it.
+/// -1 when there is none, or it is closed already
+static WAYLAND_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// The actions of the notification of a saved file, with their labels
+const FILE_ACTIONS: [(&str, &str); 4] = [
+    ("open", "Open"),
+    ("copy", "Copy to clipboard"),
+    ("edit", "Edit"),
+    ("delete", "Delete"),
+];
+
 pub static SAVEPATH: LazyLock<PathBuf> = LazyLock::new(|| {
     let Ok(home) = env::var("HOME") else {
         return PathBuf::from(TMP);
@@ -35,14 +63,15 @@ pub static SAVEPATH: LazyLock<PathBuf> = LazyLock::new(|| {
     targetpath
 });
 
+fn timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
 fn random_file_path() -> PathBuf {
-    let file_name = format!(
-        "{}-haruhui.png",
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    );
+    let file_name = format!("{}-haruhui.png", timestamp());
     SAVEPATH.join(file_name)
 }
 
@@ -56,15 +85,168 @@ enum HaruhiImageWriteError {
     FuzzySelectFailed(#[from] dialoguer::Error),
     #[error("Output not exist")]
     OutputNotExist,
+    #[error("No focused window, it needs wlr foreign toplevel management")]
+    NoFocusedToplevel,
     #[error("Wayland shot error")]
     WaylandError(#[from] libharuhishot::Error),
+    #[error("Json Error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Failed to start the clipboard process")]
+    ForkFailed(#[from] nix::errno::Errno),
+    #[error("The clipboard process failed before owning the clipboard")]
+    ClipboardFailed,
+    #[error("Command `{command}` failed, {status}")]
+    CommandFailed {
+        command: String,
+        status: std::process::ExitStatus,
+    },
+    #[error("Hook `{command}` failed on {file:?}, {status}")]
+    HookFailed {
+        command: String,
+        file: PathBuf,
+        status: std::process::ExitStatus,
+    },
+}
+
+/// Where the captured image goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Sink {
+    File,
+    Stdout,
+    /// offered on the clipboard with the path of the file, so it needs [Sink::File]
+    Clipboard,
+    /// `--edit` opens the saved file in the editor, the sinks after it get the edited image
+    Edit,
+    /// the stdin of a shell command
+    Command(String),
+}
+
+impl Sink {
+    /// What happened to the image, for the notification
+    fn destination(&self, file: Option<&Path>) -> String {
+        match self {
+            Sink::File => match file {
+                Some(file) => format!("saved to {file:?}"),
+                None => "saved".to_string(),
+            },
+            Sink::Stdout => "written to stdout".to_string(),
+            Sink::Clipboard => "copied to the clipboard".to_string(),
+            Sink::Edit => "edited".to_string(),
+            Sink::Command(command) => format!("piped to `{command}`"),
+        }
+    }
+
+    /// The sinks in the order they are written, the file comes first for the clipboard and
+    /// the editor, then the editor so the others get the edited image. The hooks need the
+    /// file too
+    fn from_args(
+        ImageSinks {
+            stdout,
+            clipboard,
+            save,
+            pipe,
+        }: ImageSinks,
+        edit: bool,
+        hooks: bool,
+    ) -> Vec<Sink> {
+        let mut sinks = Vec::new();
+        if save || clipboard || edit || hooks || (!stdout && pipe.is_empty()) {
+            sinks.push(Sink::File);
+        }
+        if edit {
+            sinks.push(Sink::Edit);
+        }
+        if stdout {
+            sinks.push(Sink::Stdout);
+        }
+        sinks.extend(pipe.into_iter().map(Sink::Command));
+        if clipboard {
+            sinks.push(Sink::Clipboard);
+        }
+        sinks
+    }
+}
+
+/// What was captured, the hooks get it as environment variables
+#[derive(Debug, Clone)]
+struct CaptureContext {
+    mode: &'static str,
+    /// the names of the outputs the image shows
+    outputs: Vec<String>,
+    /// in the global logical coordinates, unknown for a window
+    region: Option<Region>,
+    app_id: Option<String>,
+}
+
+impl CaptureContext {
+    fn output(output: &WlOutputInfo, mode: &'static str) -> Self {
+        Self {
+            mode,
+            outputs: vec![output.name().to_string()],
+            region: Some(Region {
+                position: output.position(),
+                size: output.logical_size(),
+            }),
+            app_id: None,
+        }
+    }
+
+    fn toplevel(state: &HaruhiShotState, toplevel: &TopLevel) -> Self {
+        Self {
+            mode: "application",
+            outputs: state
+                .outputs()
+                .iter()
+                .filter(|info| toplevel.outputs().contains(info.wl_output()))
+                .map(|info| info.name().to_string())
+                .collect(),
+            region: None,
+            app_id: Some(toplevel.app_id().to_string()),
+        }
+    }
+
+    /// The region like slurp prints it
+    fn geometry(&self) -> String {
+        self.region
+            .map(|Region { position, size }| {
+                format!(
+                    "{},{} {}x{}",
+                    position.x, position.y, size.width, size.height
+                )
+            })
+            .unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Clone)]
 enum HaruhiShotResult {
-    StdoutSucceeded,
-    SaveToFile(PathBuf),
-    ColorSucceeded,
+    /// the image went to `sinks`, `file` is where it was saved if [Sink::File] is one of them
+    ImageWritten {
+        file: Option<PathBuf>,
+        sinks: Vec<Sink>,
+        /// the sinks that failed, while others worked
+        failures: Vec<String>,
+        context: CaptureContext,
+        size: Size<u32>,
+    },
+    SplitSaved {
+        files: Vec<(PathBuf, CaptureContext)>,
+        summary: PathBuf,
+        /// the hooks that failed
+        failures: Vec<String>,
+    },
+    ColorPicked {
+        color: PickedColor,
+        text: String,
+        /// the clipboard process owns the text, a failed copy is an error instead
+        copied: bool,
+    },
+    ContrastChecked {
+        contrast: Contrast,
+        /// the clipboard process owns the report, a failed copy is an error instead
+        copied: bool,
+    },
+    PaletteExtracted(Vec<PaletteEntry>),
 }
 
 trait ToCaptureOption {
@@ -83,9 +265,33 @@ impl ToCaptureOption for bool {
 
 fn capture_toplevel(
     state: &mut HaruhiShotState,
-    use_stdout: bool,
+    sinks: &[Sink],
     pointer: bool,
+    pick: Option<PickMode>,
+    focused: bool,
 ) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
+    if focused {
+        let toplevel = state
+            .focused_toplevel()
+            .cloned()
+            .ok_or(HaruhiImageWriteError::NoFocusedToplevel)?;
+        let context = CaptureContext::toplevel(state, &toplevel);
+        let image_info = state.capture_toplevel(pointer.to_capture_option(), toplevel)?;
+        return write_to_image(image_info, sinks, context);
+    }
+
+    let pick = pick.unwrap_or(if stdin().is_terminal() {
+        PickMode::Tty
+    } else {
+        PickMode::Gui
+    });
+    if pick == PickMode::Gui {
+        let toplevel = state.pick_toplevel(pointer.to_capture_option())?;
+        let context = CaptureContext::toplevel(state, &toplevel);
+        let image_info = state.capture_toplevel(pointer.to_capture_option(), toplevel)?;
+        return write_to_image(image_info, sinks, context);
+    }
+
     let toplevels = state.toplevels();
     let names: Vec<String> = toplevels.iter().map(|info| info.id_and_title()).collect();
 
@@ -96,54 +302,97 @@ fn capture_toplevel(
         .interact()?;
 
     let toplevel = toplevels[selection].clone();
+    let context = CaptureContext::toplevel(state, &toplevel);
     let image_info = state.capture_toplevel(pointer.to_capture_option(), toplevel)?;
 
-    write_to_image(image_info, use_stdout)
+    write_to_image(image_info, sinks, context)
+}
+
+/// The outputs matching any of the patterns, all of them if there is no pattern, without the
+/// excluded ones
+fn select_outputs(
+    state: &HaruhiShotState,
+    patterns: &[String],
+    exclude: &[String],
+) -> Result<Vec<WlOutputInfo>, HaruhiImageWriteError> {
+    let outputs: Vec<WlOutputInfo> = state
+        .outputs()
+        .iter()
+        .filter(|info| patterns.is_empty() || patterns.iter().any(|name| info.matches(name)))
+        .filter(|info| !exclude.iter().any(|name| info.matches(name)))
+        .cloned()
+        .collect();
+    if outputs.is_empty() {
+        return Err(HaruhiImageWriteError::OutputNotExist);
+    }
+    Ok(outputs)
 }
 
 fn capture_output(
     state: &mut HaruhiShotState,
-    output: Option<String>,
-    use_stdout: bool,
+    output: Vec<String>,
+    exclude_output: Vec<String>,
+    sinks: &[Sink],
     pointer: bool,
 ) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
-    let outputs = state.outputs();
-    let names: Vec<&str> = outputs.iter().map(|info| info.name()).collect();
-
-    let selection = match output {
-        Some(name) => names
-            .iter()
-            .position(|tname| *tname == name)
-            .ok_or(HaruhiImageWriteError::OutputNotExist)?,
-        None => FuzzySelect::with_theme(&ColorfulTheme::default())
+    let output = if output.is_empty() && exclude_output.is_empty() && !stdin().is_terminal() {
+        pick_output(state)?
+    } else if output.is_empty() && exclude_output.is_empty() {
+        let outputs = state.outputs();
+        let names: Vec<&str> = outputs.iter().map(|info| info.name()).collect();
+        let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
             .with_prompt("Choose Screen")
             .default(0)
             .items(&names)
-            .interact()?,
+            .interact()?;
+        outputs[selection].clone()
+    } else {
+        let mut outputs = select_outputs(state, &output, &exclude_output)?;
+        if outputs.len() > 1 {
+            let names: Vec<&str> = outputs.iter().map(|info| info.name()).collect();
+            eprintln!(
+                "{} outputs match, they are stitched into one image: {}",
+                outputs.len(),
+                names.join(", ")
+            );
+            return capture_fullscreen(state, outputs, sinks, pointer);
+        }
+        outputs.remove(0)
     };
 
-    let output = outputs[selection].clone();
+    let context = CaptureContext::output(&output, "output");
     let image_info = state.capture_single_output(pointer.to_capture_option(), output)?;
 
-    write_to_image(image_info, use_stdout)
+    write_to_image(image_info, sinks, context)
 }
 
-fn capture_area(
+fn capture_output_under_pointer(
     state: &mut HaruhiShotState,
-    use_stdout: bool,
+    sinks: &[Sink],
     pointer: bool,
 ) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
-    let views = state.capture_area(pointer.to_capture_option(), |w_conn: &HaruhiShotState| {
-        let info = libwaysip::WaySip::new()
-            .with_connection(w_conn.connection().clone())
-            .with_selection_type(libwaysip::SelectionType::Area)
-            .get()
-            .map_err(|e| libharuhishot::Error::CaptureFailed(e.to_string()))?
-            .ok_or(libharuhishot::Error::CaptureFailed(
-                "Failed to capture the area".to_string(),
-            ))?;
-        waysip_to_region(info.size(), info.left_top_point())
-    })?;
+    let output = state.output_under_pointer()?;
+    let context = CaptureContext::output(&output, "output-under-pointer");
+    let image_info = state.capture_single_output(pointer.to_capture_option(), output)?;
+
+    write_to_image(image_info, sinks, context)
+}
+
+/// Let the user click on the output, it works without a terminal, like from a keybinding
+fn pick_output(state: &HaruhiShotState) -> Result<WlOutputInfo, HaruhiImageWriteError> {
+    let region = RegionSelector::new()
+        .with_mode(SelectionMode::Output)
+        .slurp(state)?;
+    state
+        .outputs()
+        .iter()
+        .find(|output| output.position() == region.position)
+        .cloned()
+        .ok_or(HaruhiImageWriteError::OutputNotExist)
+}
+
+/// Stitch the areas of the outputs together, and cut the selected region out
+fn clip_image(views: ClipImageViewInfo) -> Result<image::RgbaImage, HaruhiImageWriteError> {
     // Calculate the total canvas size
     let mut min_x = i32::MAX;
     let mut min_y = i32::MAX;
@@ -166,44 +415,10 @@ fn capture_area(
     let total_height = (max_y - min_y) as u32;
 
     let mut combined_image = image::RgbaImage::new(total_width, total_height);
-    for ClipImageViewInfoArea {
-        info:
-            ImageInfo {
-                data,
-                width: img_width,
-                height: img_height,
-                transform,
-                ..
-            },
-        region,
-    } in views.areas
-    {
+    for ClipImageViewInfoArea { info, region } in views.areas {
         // Load the captured image
-        let img = image::ImageBuffer::from_raw(img_width, img_height, data).ok_or(
-            HaruhiImageWriteError::ImageError(ImageError::Parameter(
-                image::error::ParameterError::from_kind(
-                    image::error::ParameterErrorKind::DimensionMismatch,
-                ),
-            )),
-        )?;
-
-        let img = match transform {
-            Transform::Normal => img,
-            Transform::_90 => image::imageops::rotate90(&img),
-            Transform::_180 => image::imageops::rotate180(&img),
-            Transform::_270 => image::imageops::rotate270(&img),
-            Transform::Flipped => image::imageops::flip_vertical(&img),
-            Transform::Flipped90 => {
-                image::imageops::flip_vertical(&image::imageops::rotate90(&img))
-            }
-            Transform::Flipped180 => {
-                image::imageops::flip_vertical(&image::imageops::rotate180(&img))
-            }
-            Transform::Flipped270 => {
-                image::imageops::flip_vertical(&image::imageops::rotate270(&img))
-            }
-            _ => unreachable!(),
-        };
+        let transform = info.transform;
+        let img = transform_image(image_from_info(info)?, transform);
         let Size { width, height } = region.display_logical_size();
         let img = image::imageops::resize(
             &img,
@@ -227,129 +442,506 @@ fn capture_area(
         }
     }
     let clip_region = views.region;
-    let image = combined_image
+    Ok(combined_image
         .view(
             (clip_region.position.x - start_x) as u32,
             (clip_region.position.y - start_y) as u32,
             clip_region.size.width as u32,
             clip_region.size.height as u32,
         )
-        .to_image();
-
-    if use_stdout {
-        let mut buff = std::io::Cursor::new(Vec::new());
-        image.write_to(&mut buff, image::ImageFormat::Png)?;
-        let content = buff.get_ref();
-        let stdout = stdout();
-        let mut writer = BufWriter::new(stdout.lock());
-        writer.write_all(content)?;
-        Ok(HaruhiShotResult::StdoutSucceeded)
-    } else {
-        let file = random_file_path();
-        image.save(&file)?;
-        Ok(HaruhiShotResult::SaveToFile(file))
-    }
-}
-fn get_color(state: &mut HaruhiShotState) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
-    let mut views = state.capture_area(CaptureOption::None, |w_conn: &HaruhiShotState| {
-        let info = libwaysip::WaySip::new()
-            .with_connection(w_conn.connection().clone())
-            .with_selection_type(libwaysip::SelectionType::Point)
-            .get()
-            .map_err(|e| libharuhishot::Error::CaptureFailed(e.to_string()))?
-            .ok_or(libharuhishot::Error::CaptureFailed(
-                "Failed to capture the area".to_string(),
-            ))?;
-        waysip_to_region(info.size(), info.left_top_point())
-    })?;
+        .to_image())
+}
+
+fn capture_area(
+    state: &mut HaruhiShotState,
+    sinks: &[Sink],
+    pointer: bool,
+) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
+    let views = state.capture_area(pointer.to_capture_option(), RegionSelector::new())?;
+    let region = views.region;
+    let context = CaptureContext {
+        mode: "area",
+        outputs: state
+            .outputs()
+            .iter()
+            .filter(|output| overlaps(output, region))
+            .map(|output| output.name().to_string())
+            .collect(),
+        region: Some(region),
+        app_id: None,
+    };
+    let image = clip_image(views)?;
+    let image_info = ImageInfo {
+        width: image.width(),
+        height: image.height(),
+        data: image.into_raw(),
+        color_type: image::ColorType::Rgba8,
+        transform: Transform::Normal,
+    };
+
+    write_to_image(image_info, sinks, context)
+}
+
+/// The output shows a part of the region
+fn overlaps(output: &WlOutputInfo, Region { position, size }: Region) -> bool {
+    let Position { x, y } = output.position();
+    let Size { width, height } = output.logical_size();
+    x < position.x + size.width
+        && position.x < x + width
+        && y < position.y + size.height
+        && position.y < y + height
+}
+
+/// What `--color` does with the picked colors
+#[derive(Debug, Clone, Copy)]
+struct ColorOptions {
+    color_format: Option<ColorFormat>,
+    average: u32,
+    copy: bool,
+    pairs: bool,
+}
+
+/// Pick a point, the color is the average of the square of `average` pixels around it
+fn pick_color(
+    state: &mut HaruhiShotState,
+    average: u32,
+) -> Result<(Position, PickedColor), HaruhiImageWriteError> {
+    let mut views = state.capture_area(
+        CaptureOption::None,
+        RegionSelector::new()
+            .with_mode(SelectionMode::Point)
+            .with_loupe(true)
+            .with_sample_size(average),
+    )?;
+    let position = views.region.position;
     let ClipImageViewInfoArea {
-        info:
-            ImageInfo {
-                data,
-                width: img_width,
-                height: img_height,
-                transform,
-                ..
-            },
-        region:
-            ClipRegion {
-                relative_region_real:
-                    Region {
-                        position: Position { x, y },
-                        size: Size { width, height },
-                    },
-                ..
-            },
+        info,
+        region: ClipRegion { display_region, .. },
     } = views.areas.remove(0);
-    let image: image::ImageBuffer<Rgba<u8>, Vec<u8>> =
-        image::ImageBuffer::from_raw(img_width, img_height, data).unwrap();
-    let img = match transform {
-        Transform::Normal => image,
-        Transform::_90 => image::imageops::rotate90(&image),
-        Transform::_180 => image::imageops::rotate180(&image),
-        Transform::_270 => image::imageops::rotate270(&image),
-        Transform::Flipped => image::imageops::flip_vertical(&image),
-        Transform::Flipped90 => image::imageops::flip_vertical(&image::imageops::rotate90(&image)),
-        Transform::Flipped180 => {
-            image::imageops::flip_vertical(&image::imageops::rotate180(&image))
-        }
-        Transform::Flipped270 => {
-            image::imageops::flip_vertical(&image::imageops::rotate270(&image))
+    let transform = info.transform;
+    let image = transform_image(image_from_info(info)?, transform);
+    let color = PickedColor::sample(&image, display_region, position, average)
+        .ok_or(HaruhiImageWriteError::OutputNotExist)?;
+    Ok((position, color))
+}
+
+fn get_color(
+    state: &mut HaruhiShotState,
+    options: ColorOptions,
+) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
+    let ColorOptions {
+        color_format,
+        average,
+        copy,
+        pairs,
+    } = options;
+    if pairs {
+        return get_contrast(state, options);
+    }
+    let (position, color) = pick_color(state, average)?;
+
+    let text = match color_format {
+        Some(ColorFormat::Json) => color.to_json(Some(position), average).to_string(),
+        Some(color_format) => color.format(color_format),
+        None => {
+            let PickedColor { r, g, b, a } = color;
+            println!("RGB: R:{r}, G:{g}, B:{b}, A:{a}");
+            println!("16hex: #{r:02x}{g:02x}{b:02x}{a:02x}");
+            color.hex()
         }
-        _ => unreachable!(),
     };
+    if color_format.is_some() {
+        println!("{text}");
+    }
+    if copy {
+        serve_clipboard(ClipboardContent::text(&text))?;
+    }
+    Ok(HaruhiShotResult::ColorPicked {
+        color,
+        text,
+        copied: copy,
+    })
+}
 
-    let clipimage = img.view(x as u32, y as u32, width as u32, height as u32);
-    let pixel = clipimage.get_pixel(0, 0);
-    println!(
-        "RGB: R:{}, G:{}, B:{}, A:{}",
-        pixel.0[0], pixel.0[1], pixel.0[2], pixel[3]
-    );
-    println!(
-        "16hex: #{:02x}{:02x}{:02x}{:02x}",
-        pixel.0[0], pixel.0[1], pixel.0[2], pixel[3]
-    );
-    Ok(HaruhiShotResult::ColorSucceeded)
+/// Pick the text color, then the background color, and report their contrast
+fn get_contrast(
+    state: &mut HaruhiShotState,
+    ColorOptions {
+        color_format,
+        average,
+        copy,
+        ..
+    }: ColorOptions,
+) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
+    let (foreground_position, foreground) = pick_color(state, average)?;
+    let (background_position, background) = pick_color(state, average)?;
+    let contrast = Contrast {
+        foreground,
+        background,
+    };
+    let text = match color_format {
+        Some(ColorFormat::Json) => contrast
+            .to_json([foreground_position, background_position], average)
+            .to_string(),
+        color_format => contrast.report(color_format),
+    };
+    println!("{text}");
+    if copy {
+        serve_clipboard(ClipboardContent::text(&text))?;
+    }
+    Ok(HaruhiShotResult::ContrastChecked {
+        contrast,
+        copied: copy,
+    })
+}
+
+/// Select an area, and find its main colors
+fn get_palette(
+    state: &mut HaruhiShotState,
+    count: u32,
+    color_format: Option<ColorFormat>,
+    stats: bool,
+) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
+    let views = state.capture_area(CaptureOption::None, RegionSelector::new())?;
+    let image = clip_image(views)?;
+    let palette = palette::median_cut(&image, count as usize);
+    if stats || color_format == Some(ColorFormat::Json) {
+        let stats = stats.then(|| palette::channel_stats(&image));
+        println!(
+            "{}",
+            palette::to_json(&palette, stats.as_ref(), color_format, &image)
+        );
+    } else {
+        println!("{}", palette::report(&palette, color_format));
+    }
+    Ok(HaruhiShotResult::PaletteExtracted(palette))
+}
+
+/// Run the task in a forked process, so the command returns at once. The task returns the exit
+/// code of the process
+fn run_in_background(task: impl FnOnce() -> i32) -> Result<(), HaruhiImageWriteError> {
+    use nix::unistd::{ForkResult, dup2_stderr, dup2_stdin, dup2_stdout, fork, setsid};
+    // the child must not write what is still buffered again
+    stdout().flush()?;
+    // SAFETY: the child only runs the task with its own connections, and exits
+    match unsafe { fork() }? {
+        ForkResult::Parent { .. } => Ok(()),
+        ForkResult::Child => {
+            let _ = setsid();
+            // the connection of the parent is never used here, and a copy of it would keep the
+            // parent's client alive for the compositor. The processes forked from this one must
+            // not close the number again, it may be reused by then
+            let fd = WAYLAND_FD.swap(-1, Ordering::Relaxed);
+            if fd >= 0 {
+                let _ = nix::unistd::close(fd);
+            }
+            // let the pipes of the caller close
+            if let Ok(null) = fs::File::options().read(true).write(true).open("/dev/null") {
+                let _ = dup2_stdin(&null);
+                let _ = dup2_stdout(&null);
+                let _ = dup2_stderr(&null);
+            }
+            std::process::exit(task());
+        }
+    }
+}
+
+/// Written by the clipboard process once it owns the clipboard
+const CLIPBOARD_OWNED: u8 = b'o';
+/// Written by the clipboard process when the compositor has no way to set the clipboard
+const CLIPBOARD_NOT_SUPPORTED: u8 = b'n';
+
+/// Keep the content on the clipboard from a forked process, like wl-copy does. The process
+/// exits when another client takes the clipboard, this returns once it owns the clipboard
+fn serve_clipboard(content: ClipboardContent) -> Result<(), HaruhiImageWriteError> {
+    let (status_read, status_write) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)?;
+    run_in_background(move || {
+        let status = fs::File::from(status_write);
+        match content.serve_with(|| {
+            let _ = (&status).write_all(&[CLIPBOARD_OWNED]);
+        }) {
+            Ok(()) => 0,
+            Err(error) => {
+                if matches!(error, libharuhishot::Error::ClipboardNotSupported) {
+                    let _ = (&status).write_all(&[CLIPBOARD_NOT_SUPPORTED]);
+                }
+                1
+            }
+        }
+    })?;
+    // the write end is only left in the process, so the read ends when it exits
+    let mut status = [0];
+    match fs::File::from(status_read).read(&mut status)? {
+        1 if status[0] == CLIPBOARD_OWNED => Ok(()),
+        1 if status[0] == CLIPBOARD_NOT_SUPPORTED => {
+            Err(libharuhishot::Error::ClipboardNotSupported.into())
+        }
+        _ => Err(HaruhiImageWriteError::ClipboardFailed),
+    }
+}
+
+const SWATCH_SIZE: u32 = 64;
+
+/// A square of the color, for the notification
+fn color_swatch(PickedColor { r, g, b, .. }: PickedColor) -> image::RgbaImage {
+    image::RgbaImage::from_pixel(SWATCH_SIZE, SWATCH_SIZE, image::Rgba([r, g, b, u8::MAX]))
+}
+
+/// A stripe for each color, as wide as the part it covers, for the notification
+fn palette_swatch(palette: &[PaletteEntry]) -> image::RgbaImage {
+    let mut swatch = image::RgbaImage::new(SWATCH_SIZE, SWATCH_SIZE);
+    let mut covered = 0.;
+    let mut start = 0;
+    for PaletteEntry { color, coverage } in palette {
+        covered += coverage;
+        let end = ((covered * SWATCH_SIZE as f64).round() as u32).min(SWATCH_SIZE);
+        let PickedColor { r, g, b, .. } = *color;
+        for x in start..end {
+            for y in 0..SWATCH_SIZE {
+                swatch.put_pixel(x, y, image::Rgba([r, g, b, u8::MAX]));
+            }
+        }
+        start = end;
+    }
+    swatch
+}
+
+/// The foreground in the middle of the background, for the notification
+fn contrast_swatch(
+    Contrast {
+        foreground,
+        background,
+    }: Contrast,
+) -> image::RgbaImage {
+    let mut swatch = color_swatch(background);
+    let PickedColor { r, g, b, .. } = foreground;
+    let border = SWATCH_SIZE / 4;
+    for y in border..SWATCH_SIZE - border {
+        for x in border..SWATCH_SIZE - border {
+            swatch.put_pixel(x, y, image::Rgba([r, g, b, u8::MAX]));
+        }
+    }
+    swatch
+}
+
+/// How the notifications are shown
+#[derive(Debug, Clone, Copy)]
+struct NotifyOptions {
+    enabled: bool,
+    urgency: NotifyUrgency,
+    /// in seconds, 0 keeps them until they are closed
+    timeout: Option<u32>,
+}
+
+impl NotifyOptions {
+    fn notification(&self) -> notify_rust::Notification {
+        use notify_rust::{Notification, Timeout, Urgency};
+        let mut notification = Notification::new();
+        notification.urgency(match self.urgency {
+            NotifyUrgency::Low => Urgency::Low,
+            NotifyUrgency::Normal => Urgency::Normal,
+            NotifyUrgency::Critical => Urgency::Critical,
+        });
+        match self.timeout {
+            None => notification.timeout(TIMEOUT),
+            Some(0) => notification.timeout(Timeout::Never),
+            Some(seconds) => {
+                notification.timeout(Timeout::Milliseconds(seconds.saturating_mul(1000)))
+            }
+        };
+        notification
+    }
 }
 
-fn notify_result(shot_result: Result<HaruhiShotResult, HaruhiImageWriteError>) {
-    use notify_rust::Notification;
+/// Do what was chosen on the notification of a saved file
+fn handle_file_action(
+    action: &str,
+    file: &Path,
+    context: &CaptureContext,
+) -> Result<(), HaruhiImageWriteError> {
+    match action {
+        // "default" is a click on the notification itself
+        "open" | "default" => {
+            Command::new("xdg-open").arg(file).status()?;
+        }
+        // served from its own process, so this one can exit
+        "copy" => serve_clipboard(image_clipboard(fs::read(file)?, Some(file)))?,
+        "edit" => run_hook(&editor_command(), file, context, false)?,
+        "delete" => fs::remove_file(file)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Show the notification, and wait for its action in the background until it is closed
+fn notify_file_actions(
+    mut notification: notify_rust::Notification,
+    file: PathBuf,
+    context: CaptureContext,
+) {
+    // without the background process it is shown without the actions
+    let plain = notification.clone();
+    for (action, label) in FILE_ACTIONS {
+        notification.action(action, label);
+    }
+    let task = move || {
+        let Ok(handle) = notification.show() else {
+            return 1;
+        };
+        let mut code = 0;
+        handle.wait_for_action(|action| {
+            if handle_file_action(action, &file, &context).is_err() {
+                code = 1;
+            }
+        });
+        code
+    };
+    if run_in_background(task).is_err() {
+        let _ = plain.show();
+    }
+}
+
+fn notify_result(
+    shot_result: Result<HaruhiShotResult, HaruhiImageWriteError>,
+    options: NotifyOptions,
+) {
+    if !options.enabled {
+        match shot_result {
+            Ok(
+                HaruhiShotResult::ImageWritten { failures, .. }
+                | HaruhiShotResult::SplitSaved { failures, .. },
+            ) => {
+                for failure in failures {
+                    eprintln!("{failure}");
+                }
+            }
+            Err(e) => eprintln!("{e}"),
+            _ => {}
+        }
+        return;
+    }
     match shot_result {
-        Ok(HaruhiShotResult::StdoutSucceeded) => {
-            let _ = Notification::new()
-                .summary("Screenshot Succeed")
-                .body("Screenshot Succeed")
+        Ok(HaruhiShotResult::ImageWritten {
+            file,
+            sinks,
+            failures,
+            context,
+            size,
+        }) => {
+            let destinations: Vec<String> = sinks
+                .iter()
+                .map(|sink| sink.destination(file.as_deref()))
+                .collect();
+            let mut body = format!(
+                "Screenshot {}×{} {}",
+                size.width,
+                size.height,
+                destinations.join(", ")
+            );
+            for failure in &failures {
+                body.push('\n');
+                body.push_str(failure);
+            }
+            let mut notification = options.notification();
+            notification
+                .summary(if failures.is_empty() {
+                    "Screenshot Succeed"
+                } else {
+                    "Screenshot Partly Failed"
+                })
+                .body(&body);
+            match file {
+                Some(file) => {
+                    notification.icon(&file.to_string_lossy());
+                    notify_file_actions(notification, file, context);
+                }
+                None => {
+                    let _ = notification.icon(SUCCEED_IMAGE).show();
+                }
+            }
+        }
+        Ok(HaruhiShotResult::SplitSaved {
+            files,
+            summary,
+            failures,
+        }) => {
+            let mut body = format!("{} outputs saved, layout in {summary:?}", files.len());
+            for failure in &failures {
+                body.push('\n');
+                body.push_str(failure);
+            }
+            let _ = options
+                .notification()
+                .summary(if failures.is_empty() {
+                    "Files Saved Succeed"
+                } else {
+                    "Files Saved, Hook Failed"
+                })
+                .body(&body)
                 .icon(SUCCEED_IMAGE)
-                .timeout(TIMEOUT)
                 .show();
         }
-        Ok(HaruhiShotResult::SaveToFile(file)) => {
-            let file_name = file.to_string_lossy().to_string();
-            let _ = Notification::new()
-                .summary("File Saved SUcceed")
-                .body(format!("File Saved to {file:?}").as_str())
-                .icon(&file_name)
-                .timeout(TIMEOUT)
-                .show();
+        Ok(HaruhiShotResult::ColorPicked {
+            color,
+            text,
+            copied,
+        }) => {
+            let mut notification = options.notification();
+            notification.summary("Color Picked").body(&if copied {
+                format!("{text} copied to the clipboard")
+            } else {
+                text
+            });
+            match notify_rust::Image::try_from(color_swatch(color)) {
+                Ok(swatch) => notification.image_data(swatch),
+                Err(_) => notification.icon(SUCCEED_IMAGE),
+            };
+            let _ = notification.show();
+        }
+        Ok(HaruhiShotResult::ContrastChecked { contrast, copied }) => {
+            let mut body = contrast.summary();
+            if copied {
+                body.push_str("\nCopied to the clipboard");
+            }
+            let mut notification = options.notification();
+            notification
+                .summary(&format!("Contrast {:.2}:1", contrast.ratio()))
+                .body(&body);
+            match notify_rust::Image::try_from(contrast_swatch(contrast)) {
+                Ok(swatch) => notification.image_data(swatch),
+                Err(_) => notification.icon(SUCCEED_IMAGE),
+            };
+            let _ = notification.show();
+        }
+        Ok(HaruhiShotResult::PaletteExtracted(palette)) => {
+            let mut notification = options.notification();
+            notification
+                .summary("Palette Extracted")
+                .body(&palette::report(&palette, None));
+            match notify_rust::Image::try_from(palette_swatch(&palette)) {
+                Ok(swatch) => notification.image_data(swatch),
+                Err(_) => notification.icon(SUCCEED_IMAGE),
+            };
+            let _ = notification.show();
         }
-        Ok(HaruhiShotResult::ColorSucceeded) => {}
         Err(e) => {
-            let _ = Notification::new()
+            let _ = options
+                .notification()
                 .summary("File Saved Failed")
                 .body(&e.to_string())
                 .icon(FAILED_IMAGE)
-                .timeout(TIMEOUT)
                 .show();
         }
     }
 }
 
+/// Capture the outputs, and stitch them together keeping their layout
 fn capture_fullscreen(
     state: &mut HaruhiShotState,
-    use_stdout: bool,
+    outputs: Vec<WlOutputInfo>,
+    sinks: &[Sink],
     pointer: bool,
 ) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
-    let outputs = state.outputs().clone();
     if outputs.is_empty() {
         return Err(HaruhiImageWriteError::OutputNotExist);
     }
@@ -380,35 +972,12 @@ fn capture_fullscreen(
     // Create a new image with the total size
     let mut combined_image = image::RgbaImage::new(total_width, total_height);
 
-    // Capture each output and copy to the combined image
-    for output in outputs {
+    // Capture all the outputs at once, and copy them to the combined image
+    let images = state.capture_outputs(pointer.to_capture_option(), &outputs)?;
+    for (output, image_info) in outputs.iter().zip(images) {
         let Size { width, height } = output.logical_size();
-        let image_info =
-            state.capture_single_output(pointer.to_capture_option(), output.clone())?;
-        let image =
-            image::ImageBuffer::from_raw(image_info.width, image_info.height, image_info.data)
-                .ok_or(HaruhiImageWriteError::ImageError(ImageError::Parameter(
-                    image::error::ParameterError::from_kind(
-                        image::error::ParameterErrorKind::DimensionMismatch,
-                    ),
-                )))?;
-        let image = match image_info.transform {
-            Transform::Normal => image,
-            Transform::_90 => image::imageops::rotate90(&image),
-            Transform::_180 => image::imageops::rotate180(&image),
-            Transform::_270 => image::imageops::rotate270(&image),
-            Transform::Flipped => image::imageops::flip_vertical(&image),
-            Transform::Flipped90 => {
-                image::imageops::flip_vertical(&image::imageops::rotate90(&image))
-            }
-            Transform::Flipped180 => {
-                image::imageops::flip_vertical(&image::imageops::rotate180(&image))
-            }
-            Transform::Flipped270 => {
-                image::imageops::flip_vertical(&image::imageops::rotate270(&image))
-            }
-            _ => unreachable!(),
-        };
+        let transform = image_info.transform;
+        let image = transform_image(image_from_info(image_info)?, transform);
         // Load the captured image
         let img = image::imageops::resize(
             &image,
@@ -441,75 +1010,308 @@ fn capture_fullscreen(
         color_type: image::ColorType::Rgba8,
         transform: libharuhishot::reexport::Transform::Normal,
     };
+    let context = CaptureContext {
+        mode: "fullscreen",
+        outputs: outputs
+            .iter()
+            .map(|output| output.name().to_string())
+            .collect(),
+        region: Some(Region {
+            position: Position { x: min_x, y: min_y },
+            size: Size {
+                width: total_width as i32,
+                height: total_height as i32,
+            },
+        }),
+        app_id: None,
+    };
 
-    write_to_image(combined_image_info, use_stdout)
+    write_to_image(combined_image_info, sinks, context)
 }
 
-pub fn waysip_to_region(
-    size: libwaysip::Size,
-    point: libwaysip::Position,
-) -> Result<Region, libharuhishot::Error> {
-    let size: Size = Size {
-        width: size.width,
-        height: size.height,
-    };
-    let position: Position = Position {
-        x: point.x,
-        y: point.y,
-    };
+/// Capture the outputs at once, and save one image for each of them, with a json summary of
+/// the layout
+fn capture_split(
+    state: &mut HaruhiShotState,
+    outputs: Vec<WlOutputInfo>,
+    pointer: bool,
+) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
+    let images = state.capture_outputs(pointer.to_capture_option(), &outputs)?;
+    let stamp = timestamp();
+    let mut files = Vec::with_capacity(outputs.len());
+    let mut layout = Vec::with_capacity(outputs.len());
+    for (output, image_info) in outputs.iter().zip(images) {
+        let transform = image_info.transform;
+        let image = transform_image(image_from_info(image_info)?, transform);
+        let file = SAVEPATH.join(format!(
+            "{stamp}-{}-haruhui.png",
+            output.name().replace('/', "_")
+        ));
+        image.save(&file)?;
 
-    Ok(Region { position, size })
+        let Position { x, y } = output.position();
+        let Size { width, height } = output.logical_size();
+        layout.push(serde_json::json!({
+            "file": file,
+            "output": output.name(),
+            "description": output.description(),
+            "x": x,
+            "y": y,
+            "logical_width": width,
+            "logical_height": height,
+            "width": image.width(),
+            "height": image.height(),
+            "scale": output.scale(),
+            "transform": format!("{transform:?}"),
+        }));
+        files.push((file, CaptureContext::output(output, "fullscreen")));
+    }
+
+    let summary = SAVEPATH.join(format!("{stamp}-haruhui.json"));
+    let content = serde_json::to_string_pretty(&layout)?;
+    fs::write(&summary, &content)?;
+    println!("{content}");
+    Ok(HaruhiShotResult::SplitSaved {
+        files,
+        summary,
+        failures: Vec::new(),
+    })
+}
+
+fn image_from_info(
+    ImageInfo {
+        data,
+        width,
+        height,
+        ..
+    }: ImageInfo,
+) -> Result<image::RgbaImage, HaruhiImageWriteError> {
+    image::ImageBuffer::from_raw(width, height, data).ok_or(HaruhiImageWriteError::ImageError(
+        ImageError::Parameter(image::error::ParameterError::from_kind(
+            image::error::ParameterErrorKind::DimensionMismatch,
+        )),
+    ))
+}
+
+/// Rotate and flip the image, so it looks like it does on the screen
+fn transform_image(image: image::RgbaImage, transform: Transform) -> image::RgbaImage {
+    match transform {
+        Transform::Normal => image,
+        Transform::_90 => image::imageops::rotate90(&image),
+        Transform::_180 => image::imageops::rotate180(&image),
+        Transform::_270 => image::imageops::rotate270(&image),
+        Transform::Flipped => image::imageops::flip_vertical(&image),
+        Transform::Flipped90 => image::imageops::flip_vertical(&image::imageops::rotate90(&image)),
+        Transform::Flipped180 => {
+            image::imageops::flip_vertical(&image::imageops::rotate180(&image))
+        }
+        Transform::Flipped270 => {
+            image::imageops::flip_vertical(&image::imageops::rotate270(&image))
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn feature_name(feature: Feature) -> &'static str {
+    match feature {
+        Feature::OutputCapture => "output capture (-O, -F)",
+        Feature::ToplevelCapture => "application capture (--application)",
+        Feature::FocusedToplevelCapture => "focused application capture (--application --focused)",
+        Feature::AreaCapture => "area and color capture (-S, -C)",
+        Feature::FreezeOverlay => "freeze the screens while selecting",
+        Feature::ExactLayout => "exact output layout with fractional scale",
+        Feature::Clipboard => "copy to the clipboard (--clipboard, -C --copy)",
+    }
+}
+
+fn print_doctor_report() -> Result<(), libharuhishot::Error> {
+    let capabilities = Capabilities::probe()?;
+    println!("Protocols:");
+    for protocol in Protocol::ALL {
+        let interface = protocol.interface();
+        match capabilities.version(protocol) {
+            Some(version) if capabilities.supports(protocol) => {
+                println!("    {interface}: version {version}");
+            }
+            Some(version) => println!(
+                "    {interface}: version {version}, needs at least {}",
+                protocol.min_version()
+            ),
+            None => println!("    {interface}: missing"),
+        }
+    }
+    println!("Features:");
+    for feature in Feature::ALL {
+        let missing = capabilities.missing(feature);
+        let name = feature_name(feature);
+        if missing.is_empty() {
+            println!("    {name}: works");
+        } else {
+            let missing: Vec<&str> = missing
+                .iter()
+                .map(|protocol| protocol.interface())
+                .collect();
+            println!("    {name}: does not work, needs {}", missing.join(", "));
+        }
+    }
+    if !capabilities.supports(Protocol::ImageCopyCapture)
+        && capabilities.supports(Protocol::WlrScreencopy)
+    {
+        println!(
+            "The compositor only provides wlr-screencopy, haruhishot needs ext-image-copy-capture"
+        );
+    }
+    Ok(())
 }
 
 fn main() {
     tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
         .init();
-    let args = HaruhiCli::parse();
-    let mut state =
-        HaruhiShotState::new().expect("Your wm needs to support Image Copy Capture protocol");
-
-    match args {
-        HaruhiCli::ListOutputs => {
-            state.print_displays_info();
+    let HaruhiArgs {
+        command,
+        timeout,
+        no_notify,
+        urgency,
+        notify_timeout,
+    } = HaruhiArgs::parse();
+    let notify = NotifyOptions {
+        enabled: !no_notify,
+        urgency,
+        timeout: notify_timeout,
+    };
+    match command {
+        HaruhiCli::Doctor => {
+            if let Err(e) = print_doctor_report() {
+                eprintln!("Failed to connect to the compositor: {e}");
+                std::process::exit(1);
+            }
         }
+        HaruhiCli::ListOutputs => connect(timeout).print_displays_info(),
         HaruhiCli::Application {
-            stdout,
+            sinks,
+            hooks,
             cursor: pointer,
-        } => notify_result(capture_toplevel(&mut state, stdout, pointer)),
+            pick,
+            focused,
+        } => {
+            let mut state = connect(timeout);
+            shoot(sinks, hooks, notify, |sinks| {
+                capture_toplevel(&mut state, sinks, pointer, pick, focused)
+            });
+        }
         HaruhiCli::Output {
             output,
-            stdout,
+            more_output,
+            exclude_output,
+            sinks,
+            hooks,
+            cursor: pointer,
+        } => {
+            let output = output.into_iter().chain(more_output).collect();
+            let mut state = connect(timeout);
+            shoot(sinks, hooks, notify, |sinks| {
+                capture_output(&mut state, output, exclude_output, sinks, pointer)
+            });
+        }
+        HaruhiCli::OutputUnderPointer {
+            sinks,
+            hooks,
             cursor: pointer,
-        } => notify_result(capture_output(&mut state, output, stdout, pointer)),
+        } => {
+            let mut state = connect(timeout);
+            shoot(sinks, hooks, notify, |sinks| {
+                capture_output_under_pointer(&mut state, sinks, pointer)
+            });
+        }
         HaruhiCli::Fullscreen {
-            stdout,
+            output,
+            exclude_output,
+            sinks,
+            hooks,
             cursor: pointer,
-        } => notify_result(capture_fullscreen(&mut state, stdout, pointer)),
+            split,
+        } => {
+            let mut state = connect(timeout);
+            shoot(sinks, hooks, notify, |sinks| {
+                select_outputs(&state, &output, &exclude_output).and_then(|outputs| {
+                    if split {
+                        capture_split(&mut state, outputs, pointer)
+                    } else {
+                        capture_fullscreen(&mut state, outputs, sinks, pointer)
+                    }
+                })
+            });
+        }
         HaruhiCli::Slurp {
-            stdout,
+            sinks,
+            hooks,
             cursor: pointer,
         } => {
-            notify_result(capture_area(&mut state, stdout, pointer));
+            let mut state = connect(timeout);
+            shoot(sinks, hooks, notify, |sinks| {
+                capture_area(&mut state, sinks, pointer)
+            });
         }
-        HaruhiCli::Color => {
-            notify_result(get_color(&mut state));
+        HaruhiCli::Color {
+            color_format,
+            average,
+            copy,
+            pairs,
+            repeat,
+        } => {
+            let mut state = connect(timeout);
+            let options = ColorOptions {
+                color_format,
+                average,
+                copy,
+                pairs,
+            };
+            loop {
+                let result = get_color(&mut state, options);
+                // Escape ends the loop, it is not a failure
+                if repeat
+                    && matches!(
+                        result,
+                        Err(HaruhiImageWriteError::WaylandError(
+                            libharuhishot::Error::Cancelled
+                        ))
+                    )
+                {
+                    break;
+                }
+                let failed = result.is_err();
+                notify_result(result, notify);
+                if !repeat || failed {
+                    break;
+                }
+            }
+        }
+        HaruhiCli::Palette {
+            count,
+            color_format,
+            stats,
+        } => {
+            let mut state = connect(timeout);
+            notify_result(get_palette(&mut state, count, color_format, stats), notify);
         }
     }
 }
 
-fn write_to_image(
-    image_info: ImageInfo,
-    use_stdout: bool,
-) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
-    if use_stdout {
-        write_to_stdout(image_info)
-    } else {
-        write_to_file(image_info)
-    }
+/// Connect to the compositor, every command but `--doctor` needs it
+fn connect(timeout: Option<u64>) -> HaruhiShotState {
+    let mut state =
+        HaruhiShotState::new().expect("Your wm needs to support Image Copy Capture protocol");
+    state.set_capture_timeout(timeout.map(std::time::Duration::from_secs));
+    WAYLAND_FD.store(
+        state.connection().backend().poll_fd().as_raw_fd(),
+        Ordering::Relaxed,
+    );
+    state
 }
 
-fn write_to_stdout(
+/// Encode the image once, and write it to every sink
+fn write_to_image(
     ImageInfo {
         data,
         width,
@@ -517,26 +1319,220 @@ fn write_to_stdout(
         color_type,
         ..
     }: ImageInfo,
+    sinks: &[Sink],
+    context: CaptureContext,
 ) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
-    let stdout = stdout();
-    let mut writer = BufWriter::new(stdout.lock());
-    PngEncoder::new(&mut writer).write_image(&data, width, height, color_type.into())?;
-    Ok(HaruhiShotResult::StdoutSucceeded)
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png).write_image(&data, width, height, color_type.into())?;
+    let mut size = Size { width, height };
+    let mut file = None;
+    let mut written = Vec::new();
+    let mut errors = Vec::new();
+    // a failing sink does not stop the others, the saved file is kept
+    for sink in sinks {
+        let result = match sink {
+            Sink::File => {
+                let path = random_file_path();
+                fs::write(&path, &png)
+                    .map(|()| file = Some(path))
+                    .map_err(HaruhiImageWriteError::FileCreatedFailed)
+            }
+            Sink::Stdout => {
+                let mut stdout = stdout().lock();
+                stdout
+                    .write_all(&png)
+                    .and_then(|()| stdout.flush())
+                    .map_err(HaruhiImageWriteError::from)
+            }
+            Sink::Command(command) => write_to_command(command, &png),
+            Sink::Clipboard => serve_clipboard(image_clipboard(png.clone(), file.as_deref())),
+            Sink::Edit => match file.as_deref() {
+                // saving it failed already
+                None => continue,
+                Some(file) => edit_file(file, &context, sinks.contains(&Sink::Stdout)).map(
+                    |(edited, edited_size)| {
+                        png = edited;
+                        size = edited_size;
+                    },
+                ),
+            },
+        };
+        match result {
+            Ok(()) => written.push(sink.clone()),
+            Err(error) => errors.push((sink, error)),
+        }
+    }
+    if written.is_empty() && !errors.is_empty() {
+        return Err(errors.swap_remove(0).1);
+    }
+    Ok(HaruhiShotResult::ImageWritten {
+        file,
+        sinks: written,
+        failures: errors
+            .into_iter()
+            .map(|(sink, error)| format!("Not {}: {error}", sink.destination(None)))
+            .collect(),
+        context,
+        size,
+    })
 }
 
-fn write_to_file(
-    ImageInfo {
-        data,
-        width,
-        height,
-        color_type,
-        ..
-    }: ImageInfo,
-) -> Result<HaruhiShotResult, HaruhiImageWriteError> {
-    let file = random_file_path();
-    let mut writer =
-        std::fs::File::create(&file).map_err(HaruhiImageWriteError::FileCreatedFailed)?;
+/// Open the saved file in the editor and wait for it, then read the edited image
+fn edit_file(
+    file: &Path,
+    context: &CaptureContext,
+    to_stderr: bool,
+) -> Result<(Vec<u8>, Size<u32>), HaruhiImageWriteError> {
+    run_hook(&editor_command(), file, context, to_stderr)?;
+    let (width, height) = image::image_dimensions(file)?;
+    Ok((fs::read(file)?, Size { width, height }))
+}
+
+/// The image as png, and the uri of the file for file managers when it is saved
+fn image_clipboard(png: Vec<u8>, file: Option<&Path>) -> ClipboardContent {
+    let content = ClipboardContent::new().with_offer("image/png", png);
+    match file {
+        Some(file) => {
+            let uri = format!("file://{}\r\n", file.display());
+            content.with_offer("text/uri-list", uri.into_bytes())
+        }
+        None => content,
+    }
+}
+
+/// Run the command with `sh -c`, and write the image to its stdin
+fn write_to_command(command: &str, png: &[u8]) -> Result<(), HaruhiImageWriteError> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    // the command may not read all of it, its status tells if it failed
+    let written = match child.stdin.take() {
+        Some(mut stdin) => match stdin.write_all(png) {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+            _ => Ok(()),
+        },
+        None => Ok(()),
+    };
+    // stdin is closed now, wait for it even if writing failed so it is not left a zombie
+    let status = child.wait()?;
+    written?;
+    if !status.success() {
+        return Err(HaruhiImageWriteError::CommandFailed {
+            command: command.to_string(),
+            status,
+        });
+    }
+    Ok(())
+}
+
+/// Open the file of the hook in the editor
+fn editor_command() -> String {
+    let editor = env::var(EDITOR_ENV).unwrap_or_else(|_| DEFAULT_EDITOR.to_string());
+    format!("{editor} \"$HARUHISHOT_FILE\"")
+}
 
-    PngEncoder::new(&mut writer).write_image(&data, width, height, color_type.into())?;
-    Ok(HaruhiShotResult::SaveToFile(file))
+/// The hook commands of `--exec`, or HARUHISHOT_EXEC without them
+fn hook_commands(exec: Vec<String>) -> Vec<String> {
+    if exec.is_empty() {
+        env::var(EXEC_ENV)
+            .ok()
+            .filter(|command| !command.trim().is_empty())
+            .into_iter()
+            .collect()
+    } else {
+        exec
+    }
+}
+
+/// Run the hooks one after another on every saved file, until one of them fails. The
+/// failure is kept in the result, the file was saved anyway. With `--split` the editor runs
+/// here too, without sinks it is not one of them
+fn run_hooks(mut result: HaruhiShotResult, edit: bool, hooks: &[String]) -> HaruhiShotResult {
+    match &mut result {
+        HaruhiShotResult::ImageWritten {
+            file: Some(file),
+            sinks,
+            failures,
+            context,
+            ..
+        } => {
+            // keep the image on stdout clean
+            let to_stderr = sinks.contains(&Sink::Stdout);
+            if let Some(Err(error)) = hooks
+                .iter()
+                .map(|hook| run_hook(hook, file, context, to_stderr))
+                .find(Result::is_err)
+            {
+                failures.push(error.to_string());
+            }
+        }
+        HaruhiShotResult::SplitSaved {
+            files, failures, ..
+        } => {
+            let editor = edit.then(editor_command);
+            for (file, context) in files.iter() {
+                if let Some(Err(error)) = editor
+                    .iter()
+                    .chain(hooks)
+                    .map(|hook| run_hook(hook, file, context, false))
+                    .find(Result::is_err)
+                {
+                    failures.push(error.to_string());
+                    break;
+                }
+            }
+        }
+        _ => {}
+    }
+    result
+}
+
+/// Run the hook with `sh -c`, and wait for it
+fn run_hook(
+    command: &str,
+    file: &Path,
+    context: &CaptureContext,
+    to_stderr: bool,
+) -> Result<(), HaruhiImageWriteError> {
+    let mut hook = Command::new("sh");
+    hook.arg("-c")
+        .arg(command)
+        .env("HARUHISHOT_FILE", file)
+        .env("HARUHISHOT_MODE", context.mode)
+        .env("HARUHISHOT_OUTPUT", context.outputs.join(","))
+        .env("HARUHISHOT_GEOMETRY", context.geometry())
+        .env(
+            "HARUHISHOT_APP_ID",
+            context.app_id.as_deref().unwrap_or_default(),
+        );
+    if to_stderr {
+        hook.stdout(std::io::stderr());
+    }
+    let status = hook.status()?;
+    if !status.success() {
+        return Err(HaruhiImageWriteError::HookFailed {
+            command: command.to_string(),
+            file: file.to_path_buf(),
+            status,
+        });
+    }
+    Ok(())
+}
+
+/// Take the screenshot into the sinks, and run the hooks on the saved file
+fn shoot(
+    sinks: ImageSinks,
+    hooks: Hooks,
+    notify: NotifyOptions,
+    capture: impl FnOnce(&[Sink]) -> Result<HaruhiShotResult, HaruhiImageWriteError>,
+) {
+    let Hooks { exec, edit } = hooks;
+    let hooks = hook_commands(exec);
+    let sinks = Sink::from_args(sinks, edit, !hooks.is_empty());
+    notify_result(
+        capture(&sinks).map(|result| run_hooks(result, edit, &hooks)),
+        notify,
+    );
 }
@@ -0,0 +1,292 @@
+use image::RgbaImage;
+use serde_json::json;
+
+use crate::{clapargs::ColorFormat, color::PickedColor};
+
+/// The bits of each channel kept when counting the colors, the palette colors are the average
+/// of the full colors in their box
+const BIN_BITS: u32 = 5;
+
+/// A color of the palette, and how much of the region it covers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PaletteEntry {
+    pub color: PickedColor,
+    /// from 0 to 1
+    pub coverage: f64,
+}
+
+/// The pixels whose colors are the same with [BIN_BITS] bits
+#[derive(Debug, Clone, Copy, Default)]
+struct Bin {
+    key: [u8; 3],
+    sums: [u64; 3],
+    count: u64,
+}
+
+fn population(bins: &[Bin]) -> u64 {
+    bins.iter().map(|bin| bin.count).sum()
+}
+
+/// The channel the bins spread the most over, and how far
+fn widest_channel(bins: &[Bin]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let keys = bins.iter().map(|bin| bin.key[channel]);
+            let range = keys.clone().max().unwrap_or(0) - keys.min().unwrap_or(0);
+            (channel, range)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+/// Find up to `count` main colors with median cut, the most covering first
+pub fn median_cut(image: &RgbaImage, count: usize) -> Vec<PaletteEntry> {
+    let mut bins = vec![Bin::default(); 1 << (BIN_BITS * 3)];
+    for pixel in image.pixels() {
+        let [r, g, b, _] = pixel.0;
+        let key = [r, g, b].map(|channel| channel >> (8 - BIN_BITS));
+        let index = key
+            .iter()
+            .fold(0, |index, key| index << BIN_BITS | *key as usize);
+        let bin = &mut bins[index];
+        bin.key = key;
+        for (sum, channel) in bin.sums.iter_mut().zip([r, g, b]) {
+            *sum += channel as u64;
+        }
+        bin.count += 1;
+    }
+    let total = population(&bins);
+    if total == 0 {
+        return Vec::new();
+    }
+
+    let mut boxes: Vec<Vec<Bin>> = vec![bins.into_iter().filter(|bin| bin.count > 0).collect()];
+    while boxes.len() < count {
+        // split the box with the most pixels spread the widest
+        let Some((index, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, bins)| {
+                let (channel, range) = widest_channel(bins);
+                (range > 0).then(|| (index, channel, range as u64 * population(bins)))
+            })
+            .max_by_key(|(_, _, score)| *score)
+            .map(|(index, channel, _)| (index, channel))
+        else {
+            break;
+        };
+        let mut lower = boxes.swap_remove(index);
+        lower.sort_unstable_by_key(|bin| bin.key[channel]);
+        let half = population(&lower) / 2;
+        let mut seen = 0;
+        let median = lower
+            .iter()
+            .position(|bin| {
+                seen += bin.count;
+                seen >= half
+            })
+            .unwrap_or(0);
+        let upper = lower.split_off((median + 1).clamp(1, lower.len() - 1));
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+
+    let mut palette: Vec<PaletteEntry> = boxes
+        .iter()
+        .map(|bins| {
+            let count = population(bins);
+            let [r, g, b] = [0, 1, 2].map(|channel| {
+                let sum: u64 = bins.iter().map(|bin| bin.sums[channel]).sum();
+                ((sum + count / 2) / count) as u8
+            });
+            PaletteEntry {
+                color: PickedColor {
+                    r,
+                    g,
+                    b,
+                    a: u8::MAX,
+                },
+                coverage: count as f64 / total as f64,
+            }
+        })
+        .collect();
+    palette.sort_by(|a, b| b.coverage.total_cmp(&a.coverage));
+    palette
+}
+
+/// The statistics of a channel over the region
+#[derive(Debug, Clone)]
+pub struct ChannelStats {
+    pub min: u8,
+    pub max: u8,
+    pub mean: f64,
+    /// the count of pixels for every value
+    pub histogram: Vec<u64>,
+}
+
+/// The statistics of the red, green and blue channels
+pub fn channel_stats(image: &RgbaImage) -> [ChannelStats; 3] {
+    let mut histograms = [[0u64; 256]; 3];
+    for pixel in image.pixels() {
+        for (histogram, channel) in histograms.iter_mut().zip(pixel.0) {
+            histogram[channel as usize] += 1;
+        }
+    }
+    let total = image.width() as u64 * image.height() as u64;
+    histograms.map(|histogram| {
+        let mut values = (0..=u8::MAX).filter(|value| histogram[*value as usize] > 0);
+        let min = values.next().unwrap_or(0);
+        let max = values.next_back().unwrap_or(min);
+        let sum: u64 = (0..256)
+            .map(|value| value * histogram[value as usize])
+            .sum();
+        ChannelStats {
+            min,
+            max,
+            mean: sum as f64 / total.max(1) as f64,
+            histogram: histogram.to_vec(),
+        }
+    })
+}
+
+/// The text of a color, hex unless another format is given
+fn color_text(color: PickedColor, color_format: Option<ColorFormat>) -> String {
+    match color_format {
+        None | Some(ColorFormat::Json) => color.hex(),
+        Some(color_format) => color.format(color_format),
+    }
+}
+
+/// A line for each color, with its coverage
+pub fn report(palette: &[PaletteEntry], color_format: Option<ColorFormat>) -> String {
+    palette
+        .iter()
+        .map(|PaletteEntry { color, coverage }| {
+            format!(
+                "{} {:.2}%",
+                color_text(*color, color_format),
+                coverage * 100.
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The palette, and the statistics of the channels when given
+pub fn to_json(
+    palette: &[PaletteEntry],
+    stats: Option<&[ChannelStats; 3]>,
+    color_format: Option<ColorFormat>,
+    image: &RgbaImage,
+) -> serde_json::Value {
+    let palette: Vec<serde_json::Value> = palette
+        .iter()
+        .map(|PaletteEntry { color, coverage }| {
+            json!({
+                "color": color_text(*color, color_format),
+                "rgb": [color.r, color.g, color.b],
+                "coverage": (coverage * 10000.).round() / 100.,
+            })
+        })
+        .collect();
+    let mut json = json!({
+        "width": image.width(),
+        "height": image.height(),
+        "palette": palette,
+    });
+    if let Some(stats) = stats {
+        for (name, stats) in ["r", "g", "b"].into_iter().zip(stats) {
+            json["channels"][name] = json!({
+                "min": stats.min,
+                "max": stats.max,
+                "mean": (stats.mean * 100.).round() / 100.,
+                "histogram": stats.histogram,
+            });
+        }
+    }
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel_stats, median_cut};
+    use crate::color::PickedColor;
+    use image::{Rgba, RgbaImage};
+
+    const fn rgb(r: u8, g: u8, b: u8) -> PickedColor {
+        PickedColor { r, g, b, a: 255 }
+    }
+
+    /// A column of `width` pixels for each color
+    fn stripes(colors: &[([u8; 3], u32)]) -> RgbaImage {
+        let width = colors.iter().map(|(_, width)| width).sum();
+        let mut image = RgbaImage::new(width, 2);
+        let mut x = 0;
+        for ([r, g, b], width) in colors {
+            for _ in 0..*width {
+                for y in 0..2 {
+                    image.put_pixel(x, y, Rgba([*r, *g, *b, 255]));
+                }
+                x += 1;
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn single_color() {
+        let image = RgbaImage::from_pixel(8, 8, Rgba([12, 34, 56, 255]));
+        let palette = median_cut(&image, 5);
+        assert_eq!(palette.len(), 1);
+        assert_eq!(palette[0].color, rgb(12, 34, 56));
+        assert_eq!(palette[0].coverage, 1.);
+    }
+
+    #[test]
+    fn count_over_distinct_colors() {
+        let image = stripes(&[([255, 0, 0], 5), ([0, 255, 0], 3), ([0, 0, 255], 2)]);
+        let palette = median_cut(&image, 8);
+        let colors: Vec<_> = palette.iter().map(|entry| entry.color).collect();
+        assert_eq!(colors, [rgb(255, 0, 0), rgb(0, 255, 0), rgb(0, 0, 255)]);
+        let coverages: Vec<_> = palette.iter().map(|entry| entry.coverage).collect();
+        assert_eq!(coverages, [0.5, 0.3, 0.2]);
+    }
+
+    #[test]
+    fn one_color_is_the_average() {
+        let image = stripes(&[([0, 0, 0], 1), ([200, 100, 50], 1)]);
+        let palette = median_cut(&image, 1);
+        assert_eq!(palette.len(), 1);
+        assert_eq!(palette[0].color, rgb(100, 50, 25));
+    }
+
+    #[test]
+    fn empty_image() {
+        assert!(median_cut(&RgbaImage::new(0, 0), 3).is_empty());
+        let stats = channel_stats(&RgbaImage::new(0, 0));
+        assert!(stats.iter().all(|stats| stats.mean == 0.));
+    }
+
+    #[test]
+    fn stats_of_a_single_color() {
+        let image = RgbaImage::from_pixel(4, 4, Rgba([12, 34, 56, 255]));
+        let [r, g, b] = channel_stats(&image);
+        for (stats, value) in [(r, 12), (g, 34), (b, 56)] {
+            assert_eq!((stats.min, stats.max), (value, value));
+            assert_eq!(stats.mean, value as f64);
+            assert_eq!(stats.histogram[value as usize], 16);
+            assert_eq!(stats.histogram.iter().sum::<u64>(), 16);
+        }
+    }
+
+    #[test]
+    fn stats_of_stripes() {
+        let image = stripes(&[([10, 0, 255], 1), ([30, 0, 255], 3)]);
+        let [r, g, b] = channel_stats(&image);
+        assert_eq!((r.min, r.max, r.mean), (10, 30, 25.));
+        assert_eq!((r.histogram[10], r.histogram[30]), (2, 6));
+        assert_eq!((g.min, g.max, g.mean), (0, 0, 0.));
+        assert_eq!((b.min, b.max, b.mean), (255, 255, 255.));
+        assert_eq!(b.histogram.len(), 256);
+    }
+}